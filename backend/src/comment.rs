@@ -0,0 +1,241 @@
+use chrono::{DateTime, Utc};
+use rocket::{futures::TryStreamExt, serde::json::Json};
+use rocket_db_pools::mongodb::{self, bson::{doc, oid::ObjectId, serde_helpers::{chrono_datetime_as_bson_datetime, chrono_datetime_as_bson_datetime_optional}}};
+use serde::{Deserialize, Serialize};
+
+use crate::{authentication::{AuthenticationError, UserGuard}, db::DBWrapper, response::{ApiErrorType, ApiResponder, ApiResponse}, user::{Permissions, User}, video::Video};
+
+const MAX_TEXT_LEN: usize = 2000;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Comment {
+    #[serde(rename = "_id")]
+    pub id: String,
+    video: String,
+    author: String,
+    // comment this one replies to
+    parent: Option<String>,
+    text: String,
+    // playback position the comment refers to, in seconds
+    timestamp: Option<f64>,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    created: DateTime<Utc>,
+    #[serde(default, with = "chrono_datetime_as_bson_datetime_optional")]
+    edited: Option<DateTime<Utc>>,
+    // comments with replies are only blanked when deleted, to keep the thread
+    #[serde(default)]
+    deleted: bool,
+}
+
+impl ApiResponse for Comment {}
+
+impl DBWrapper {
+    async fn get_comment(&self, video: &str, id: &str) -> Result<Option<Comment>, mongodb::error::Error> {
+        self
+            .collection::<Comment>(Self::COMMENTS)
+            .find_one(doc! {"_id": id, "video": video}, None)
+            .await
+    }
+
+    async fn get_video_comments(&self, video: &str) -> Result<Vec<Comment>, mongodb::error::Error> {
+        self
+            .collection::<Comment>(Self::COMMENTS)
+            .find(doc! {"video": video}, mongodb::options::FindOptions::builder().sort(doc! {"created": 1}).build())
+            .await?
+            .try_collect()
+            .await
+    }
+
+    async fn save_comment(&self, comment: &Comment) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<Comment>(Self::COMMENTS)
+            .replace_one(doc! {"_id": &comment.id}, comment, mongodb::options::ReplaceOptions::builder().upsert(true).build())
+            .await
+            .map(|_| ())
+    }
+
+    pub(crate) async fn delete_video_comments(&self, video: &str) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<Comment>(Self::COMMENTS)
+            .delete_many(doc! {"video": video}, None)
+            .await
+            .map(|_| ())
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum CommentError {
+    VideoNotFound,
+    CommentNotFound,
+    InvalidText,
+    InvalidTimestamp,
+}
+
+impl ApiErrorType for CommentError {
+    fn ty(&self) -> &'static str {
+        match self {
+            Self::VideoNotFound => "video_not_found",
+            Self::CommentNotFound => "comment_not_found",
+            Self::InvalidText => "invalid_text",
+            Self::InvalidTimestamp => "invalid_timestamp",
+        }
+    }
+
+    fn status(&self) -> rocket::http::Status {
+        match self {
+            Self::VideoNotFound => rocket::http::Status::NotFound,
+            Self::CommentNotFound => rocket::http::Status::NotFound,
+            Self::InvalidText => rocket::http::Status::BadRequest,
+            Self::InvalidTimestamp => rocket::http::Status::BadRequest,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::VideoNotFound => "Video not found".to_string(),
+            Self::CommentNotFound => "Comment not found".to_string(),
+            Self::InvalidText => format!("Comment must be between 1 and {} characters", MAX_TEXT_LEN),
+            Self::InvalidTimestamp => "Timestamp is outside of the video".to_string(),
+        }
+    }
+}
+
+// fetches the video if the user can see it, hiding its existence otherwise
+async fn authorized_video(video: &str, user: &User, db: &DBWrapper) -> Result<Video, crate::response::ApiError> {
+    match db.get_video_resolved(video).await? {
+        Some(v) if v.user_authorized(Some(user), db).await? => Ok(v),
+        _ => Err(CommentError::VideoNotFound.into()),
+    }
+}
+
+fn validate(text: &str, timestamp: Option<f64>, video: &Video) -> Result<String, CommentError> {
+    let text = text.trim();
+    if text.is_empty() || text.chars().count() > MAX_TEXT_LEN {
+        return Err(CommentError::InvalidText);
+    }
+    if let Some(t) = timestamp {
+        let duration = video.file.as_ref().unwrap_right().duration;
+        if !t.is_finite() || t < 0. || duration.is_some_and(|d| t > d) {
+            return Err(CommentError::InvalidTimestamp);
+        }
+    }
+    Ok(text.to_string())
+}
+
+#[derive(Serialize)]
+#[serde(transparent)]
+pub(crate) struct ListResponse {
+    inner: Vec<Comment>,
+}
+
+impl ApiResponse for ListResponse {}
+
+// all comments of a video, oldest first. threads are rebuilt by clients through `parent`
+#[get("/<video>/comments")]
+pub(crate) async fn list(video: &str, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<ListResponse> {
+    let user = user?.user;
+    let video = match authorized_video(video, &user, &db).await {
+        Ok(v) => v,
+        Err(e) => return ApiResponder::Err(e),
+    };
+    ListResponse { inner: db.get_video_comments(&video.id).await? }.into()
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AddForm {
+    text: String,
+    timestamp: Option<f64>,
+    parent: Option<String>,
+}
+
+#[post("/<video>/comments", data = "<form>", format = "json")]
+pub(crate) async fn add(video: &str, form: Json<AddForm>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<Comment> {
+    let user = user?.user;
+    let video = match authorized_video(video, &user, &db).await {
+        Ok(v) => v,
+        Err(e) => return ApiResponder::Err(e),
+    };
+    let form = form.into_inner();
+    let text = validate(&form.text, form.timestamp, &video)?;
+    if let Some(ref parent) = form.parent {
+        if db.get_comment(&video.id, parent).await?.is_none() {
+            return ApiResponder::Err(CommentError::CommentNotFound.into());
+        }
+    }
+    let comment = Comment {
+        id: ObjectId::new().to_hex(),
+        video: video.id,
+        author: user.username,
+        parent: form.parent,
+        text,
+        timestamp: form.timestamp,
+        created: Utc::now(),
+        edited: None,
+        deleted: false,
+    };
+    db.save_comment(&comment).await?;
+    comment.into()
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EditForm {
+    text: String,
+    timestamp: Option<f64>,
+}
+
+// only the author can edit a comment
+#[post("/<video>/comments/<id>", data = "<form>", format = "json")]
+pub(crate) async fn edit(video: &str, id: &str, form: Json<EditForm>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<Comment> {
+    let user = user?.user;
+    let video = match authorized_video(video, &user, &db).await {
+        Ok(v) => v,
+        Err(e) => return ApiResponder::Err(e),
+    };
+    let mut comment = match db.get_comment(&video.id, id).await? {
+        Some(c) if !c.deleted => c,
+        _ => return ApiResponder::Err(CommentError::CommentNotFound.into()),
+    };
+    if comment.author != user.username {
+        return AuthenticationError::InsufficientPermissions(Permissions::ADMIN).into();
+    }
+    let form = form.into_inner();
+    comment.text = validate(&form.text, form.timestamp, &video)?;
+    comment.timestamp = form.timestamp;
+    comment.edited = Some(Utc::now());
+    db.save_comment(&comment).await?;
+    comment.into()
+}
+
+// authors, admins and the video owner can delete a comment
+#[delete("/<video>/comments/<id>")]
+pub(crate) async fn delete(video: &str, id: &str, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<()> {
+    let user = user?.user;
+    let video = match authorized_video(video, &user, &db).await {
+        Ok(v) => v,
+        Err(e) => return ApiResponder::Err(e),
+    };
+    let mut comment = match db.get_comment(&video.id, id).await? {
+        Some(c) if !c.deleted => c,
+        _ => return ApiResponder::Err(CommentError::CommentNotFound.into()),
+    };
+    if comment.author != user.username && !video.is_owner(&user) && !user.allowed(Permissions::ADMIN) {
+        return AuthenticationError::InsufficientPermissions(Permissions::ADMIN).into();
+    }
+    let replies = db
+        .collection::<Comment>(DBWrapper::COMMENTS)
+        .count_documents(doc! {"parent": &comment.id}, None)
+        .await?;
+    if replies > 0 {
+        comment.text = String::new();
+        comment.timestamp = None;
+        comment.deleted = true;
+        db.save_comment(&comment).await?;
+    } else {
+        db
+            .collection::<Comment>(DBWrapper::COMMENTS)
+            .delete_one(doc! {"_id": &comment.id}, None)
+            .await?;
+    }
+    ApiResponder::Ok(())
+}
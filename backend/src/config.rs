@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{net::IpAddr, path::PathBuf};
 
 use chrono::TimeDelta;
 use lazy_static::lazy_static;
@@ -20,6 +20,29 @@ pub struct MeTube {
     pub(crate) cors: CorsConfig,
     pub database: String,
     pub(crate) media_chunk: u64,
+    // address the site is reached at, used for absolute links in share pages.
+    //   derived from the request headers if unset
+    #[serde(default)]
+    pub(crate) public_url: Option<String>,
+    // addresses of the reverse proxies whose `X-Forwarded-*` headers are trusted when `public_url` is unset
+    #[serde(default)]
+    pub(crate) trusted_proxies: Vec<IpAddr>,
+    #[serde(default)]
+    pub(crate) jobs: JobsConfig,
+    #[serde(default)]
+    pub(crate) hls: HlsConfig,
+    #[serde(default)]
+    pub(crate) uploads: UploadsConfig,
+    #[serde(default)]
+    pub(crate) tools: ToolsConfig,
+    #[serde(default)]
+    pub(crate) sprites: SpritesConfig,
+    #[serde(default)]
+    pub(crate) storage: StorageConfig,
+    #[serde(default)]
+    pub(crate) quotas: QuotasConfig,
+    #[serde(default)]
+    pub(crate) analytics: AnalyticsConfig,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -30,18 +53,223 @@ pub(crate) struct CorsConfig {
     // pub(crate) allow_credentials: bool,
 }
 
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub(crate) struct JobsConfig {
+    // disable to run the api without processing any background job
+    pub(crate) enabled: bool,
+    pub(crate) max_attempts: u32,
+    #[serde_as(as = "DurationSeconds<f64>")]
+    pub(crate) poll_interval: TimeDelta,
+    #[serde_as(as = "DurationSeconds<f64>")]
+    pub(crate) retry_delay: TimeDelta,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_attempts: 3,
+            poll_interval: TimeDelta::seconds(5),
+            retry_delay: TimeDelta::minutes(5),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub(crate) struct HlsConfig {
+    // generate hls renditions for every uploaded file
+    pub(crate) enabled: bool,
+    // heights of the scaled renditions, the source resolution is always included
+    pub(crate) renditions: Vec<u32>,
+    // target segment length, in seconds
+    pub(crate) segment_duration: u32,
+}
+
+impl Default for HlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            renditions: vec![360, 720],
+            segment_duration: 6,
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub(crate) struct UploadsConfig {
+    // resumable upload sessions are removed after this much inactivity
+    #[serde_as(as = "DurationSeconds<f64>")]
+    pub(crate) expiration: TimeDelta,
+    // in bytes
+    pub(crate) max_size: Option<u64>,
+}
+
+impl Default for UploadsConfig {
+    fn default() -> Self {
+        Self {
+            expiration: TimeDelta::days(1),
+            max_size: None,
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub(crate) struct ToolsConfig {
+    // paths of the ffmpeg binaries, looked up in PATH if not absolute
+    pub(crate) ffmpeg: String,
+    pub(crate) ffprobe: String,
+    // applies to ffprobe and other quick operations, like thumbnail extraction
+    #[serde_as(as = "DurationSeconds<f64>")]
+    pub(crate) probe_timeout: TimeDelta,
+    // applies to long ffmpeg processes, like transcoding
+    #[serde_as(as = "DurationSeconds<f64>")]
+    pub(crate) process_timeout: TimeDelta,
+    // maximum number of external processes running at the same time
+    pub(crate) max_concurrent: usize,
+}
+
+impl Default for ToolsConfig {
+    fn default() -> Self {
+        Self {
+            ffmpeg: "ffmpeg".to_string(),
+            ffprobe: "ffprobe".to_string(),
+            probe_timeout: TimeDelta::minutes(1),
+            process_timeout: TimeDelta::hours(4),
+            max_concurrent: 4,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub(crate) struct SpritesConfig {
+    // generate seek bar previews for every uploaded video
+    pub(crate) enabled: bool,
+    // seconds between two frames, raised for long videos to respect max_frames
+    pub(crate) interval: f64,
+    pub(crate) max_frames: u32,
+    pub(crate) columns: u32,
+    // width of a single frame, in pixels
+    pub(crate) width: u32,
+}
+
+impl Default for SpritesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: 10.,
+            max_frames: 400,
+            columns: 10,
+            width: 160,
+        }
+    }
+}
+
+// bytes of stored files, converted versions included. unlimited if unset
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub(crate) struct QuotasConfig {
+    // for the videos uploaded by each user
+    pub(crate) user: Option<u64>,
+    // for the videos of each game
+    pub(crate) game: Option<u64>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub(crate) struct AnalyticsConfig {
+    // daily view counts are removed after this long, keep it above the longest range the analytics endpoints allow
+    #[serde_as(as = "DurationSeconds<f64>")]
+    pub(crate) retention: TimeDelta,
+    // mixed into the hashes that identify viewers, they are never stored in clear.
+    //   a random one is used if unset, viewers are then counted again after every restart
+    pub(crate) viewer_salt: Option<String>,
+}
+
+impl Default for AnalyticsConfig {
+    fn default() -> Self {
+        Self {
+            retention: TimeDelta::days(400),
+            viewer_salt: None,
+        }
+    }
+}
+
+// where video files and thumbnails are kept.
+//   everything derived from them stays in `video_storage`: subtitles are kept there with s3 too,
+//   hls renditions and sprites are served from disk only and can't be enabled with s3
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub(crate) enum StorageConfig {
+    // in `video_storage`
+    #[default]
+    Local,
+    S3(S3Config),
+}
+
+// any S3 compatible service, like aws or minio
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct S3Config {
+    // like `https://s3.eu-west-1.amazonaws.com` or `http://localhost:9000`
+    pub(crate) endpoint: String,
+    pub(crate) bucket: String,
+    #[serde(default = "S3Config::default_region")]
+    pub(crate) region: String,
+    pub(crate) access_key: String,
+    pub(crate) secret_key: String,
+    // prepended to every key, like `metube/`
+    #[serde(default)]
+    pub(crate) prefix: String,
+    // `<endpoint>/<bucket>/<key>` instead of `<bucket>.<endpoint>/<key>`, needed by minio
+    #[serde(default = "S3Config::default_path_style")]
+    pub(crate) path_style: bool,
+}
+
+impl S3Config {
+    fn default_region() -> String {
+        "us-east-1".to_string()
+    }
+
+    fn default_path_style() -> bool {
+        true
+    }
+}
+
 impl MeTube {
     pub(crate) fn check(&self) {
+        if matches!(self.storage, StorageConfig::S3(_)) && (self.hls.enabled || self.sprites.enabled) {
+            panic!("HLS and sprites are not supported with the s3 storage");
+        }
+        if self.analytics.retention < TimeDelta::zero() {
+            panic!("Analytics retention must not be negative");
+        }
         if !PathBuf::from(&self.video_storage).exists() {
             panic!("Video storage path does not exist");
         } else {
-            // if upload/thumbs does not exist, create it
-            let thumbs = PathBuf::from(&self.video_storage).join("thumbs");
-            if !thumbs.exists() {
-                std::fs::create_dir_all(thumbs).expect("Failed to create thumbs directory");
+            // create storage subdirectories if they do not exist
+            for dir in ["thumbs", "transcode", "hls", "uploads", "sprites", "subtitles", "downloads"] {
+                let dir = PathBuf::from(&self.video_storage).join(dir);
+                if !dir.exists() {
+                    std::fs::create_dir_all(dir).expect("Failed to create storage subdirectory");
+                }
             }
         }
     }
+
+    // settings that work, but are likely mistakes
+    pub(crate) fn warn(&self) {
+        if self.analytics.viewer_salt.is_none() {
+            log::warn!("analytics.viewer_salt is unset, a random one is used and unique viewers will be counted again after every restart");
+        }
+    }
 }
 
 lazy_static!{
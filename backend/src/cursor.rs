@@ -0,0 +1,125 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rocket_db_pools::mongodb::bson::{self, doc, Bson, Document};
+use serde::{Deserialize, Serialize};
+
+use crate::response::ApiErrorType;
+
+pub(crate) const NEXT_CURSOR_HEADER: &str = "X-Next-Cursor";
+
+// position in a listing: the sort key and `_id` of the last returned item.
+//   clients get it as an opaque string and pass it back to get the next page.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Cursor {
+    // identifies the ordering, a cursor can't be reused with a different one
+    #[serde(rename = "s")]
+    pub(crate) sort: String,
+    #[serde(rename = "k")]
+    pub(crate) key: Bson,
+    #[serde(rename = "i")]
+    pub(crate) id: Bson,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum CursorError {
+    InvalidCursor,
+}
+
+impl ApiErrorType for CursorError {
+    fn ty(&self) -> &'static str {
+        match self {
+            Self::InvalidCursor => "invalid_cursor",
+        }
+    }
+
+    fn status(&self) -> rocket::http::Status {
+        match self {
+            Self::InvalidCursor => rocket::http::Status::BadRequest,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::InvalidCursor => "Invalid or expired cursor".to_string(),
+        }
+    }
+}
+
+impl Cursor {
+    pub(crate) fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(bson::to_vec(self).expect("cursor is always serializable"))
+    }
+
+    // fails if the cursor is malformed or was made for another ordering
+    pub(crate) fn decode(s: &str, sort: &str) -> Result<Self, CursorError> {
+        let bytes = URL_SAFE_NO_PAD.decode(s).map_err(|_| CursorError::InvalidCursor)?;
+        let cursor: Self = bson::from_slice(&bytes).map_err(|_| CursorError::InvalidCursor)?;
+        if cursor.sort == sort {
+            Ok(cursor)
+        } else {
+            Err(CursorError::InvalidCursor)
+        }
+    }
+
+    // builds the cursor pointing after `doc`, which must contain `key_field`
+    pub(crate) fn after(sort: &str, doc: &Document, key_field: &str) -> Option<Self> {
+        Some(Self {
+            sort: sort.to_string(),
+            key: doc.get(key_field)?.clone(),
+            id: doc.get("_id")?.clone(),
+        })
+    }
+
+    // matches the items coming after the cursor, for a `{key_field: order, _id: order}` sort
+    pub(crate) fn filter(&self, key_field: &str, order: i32) -> Document {
+        let op = if order < 0 { "$lt" } else { "$gt" };
+        doc! { "$or": [
+            { key_field: { op: &self.key } },
+            { key_field: &self.key, "_id": { op: &self.id } },
+        ] }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Status;
+
+    use super::*;
+
+    fn cursor() -> Cursor {
+        Cursor::after("added:-1", &doc! { "_id": "abc", "added": 42 }, "added").unwrap()
+    }
+
+    fn rejected(s: &str, sort: &str) -> bool {
+        match Cursor::decode(s, sort) {
+            Err(e) => e.status() == Status::BadRequest,
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let decoded = Cursor::decode(&cursor().encode(), "added:-1").ok().unwrap();
+        assert_eq!(decoded.sort, "added:-1");
+        assert_eq!(decoded.key, Bson::Int32(42));
+        assert_eq!(decoded.id, Bson::String("abc".to_string()));
+    }
+
+    #[test]
+    fn foreign_sort_is_rejected() {
+        assert!(rejected(&cursor().encode(), "name:1"));
+    }
+
+    #[test]
+    fn malformed_is_rejected() {
+        assert!(rejected("", "added:-1"));
+        assert!(rejected("not a cursor!", "added:-1"));
+        // valid base64, not a cursor document
+        assert!(rejected(&URL_SAFE_NO_PAD.encode(b"garbage"), "added:-1"));
+        assert!(rejected(&URL_SAFE_NO_PAD.encode(bson::to_vec(&doc! { "s": "added:-1" }).unwrap()), "added:-1"));
+        // tampered
+        let mut tampered = cursor().encode();
+        tampered.truncate(tampered.len() - 4);
+        assert!(rejected(&tampered, "added:-1"));
+    }
+}
@@ -9,6 +9,18 @@ use crate::authentication::AuthenticationError;
 use crate::config::CONFIG;
 use crate::response::ApiErrorType;
 
+// mongodb error codes handled by the api
+pub(crate) const DUPLICATE_KEY: i32 = 11000;
+const INDEX_OPTIONS_CONFLICT: i32 = 85;
+
+pub(crate) fn error_code(e: &mongodb::error::Error) -> Option<i32> {
+    match *e.kind {
+        mongodb::error::ErrorKind::Command(ref c) => Some(c.code),
+        mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(ref w)) => Some(w.code),
+        _ => None,
+    }
+}
+
 #[derive(Database)]
 #[database("metube")]
 pub struct Db(Client);
@@ -23,24 +35,106 @@ impl DBWrapper {
     pub const GAME_USERS: &'static str = "game_users";
     pub const LIKES: &'static str = "likes";
     pub const VIDEO_TOKENS: &'static str = "video_tokens";
+    pub const JOBS: &'static str = "jobs";
+    pub const UPLOAD_SESSIONS: &'static str = "upload_sessions";
+    pub const SUBTITLES: &'static str = "subtitles";
+    pub const PLAYLISTS: &'static str = "playlists";
+    pub const COMMENTS: &'static str = "comments";
+    pub const HISTORY: &'static str = "history";
+    pub const RECENT_VIEWS: &'static str = "recent_views";
+    pub const VIEW_STATS: &'static str = "view_stats";
+    pub const SHARE_LINKS: &'static str = "share_links";
+    pub const SHARE_LINK_VIEWS: &'static str = "share_link_views";
+    pub const QUOTA_RESERVATIONS: &'static str = "quota_reservations";
 
     pub fn new(db: Client) -> Self {
         Self(db)
     }
 
-    async fn _enforce_constraints(&self) {
+    async fn _enforce_constraints(&self) -> mongodb::error::Result<()> {
         let unique_options = mongodb::options::IndexOptions::builder().unique(true).build();
 
-        for (c, d) in vec![
+        for (c, d) in [
             (Self::USERS, doc! {"username": 1}),
             (Self::GAME_USERS, doc! {"game": 1, "user": 1}),
             (Self::LIKES, doc! {"user": 1, "video": 1}),
+            (Self::HISTORY, doc! {"user": 1, "video": 1}),
+            (Self::VIEW_STATS, doc! {"video": 1, "day": 1}),
+            (Self::RECENT_VIEWS, doc! {"video": 1, "viewer": 1}),
+            (Self::SHARE_LINK_VIEWS, doc! {"link": 1, "viewer": 1}),
         ] {
             self.database()
                 .collection::<()>(c)
                 .create_index(IndexModel::builder().keys(d).options(unique_options.clone()).build(), None)
-                .await.unwrap();
+                .await?;
         }
+
+        // each content is stored once, converted files have no hash
+        let hash_options = mongodb::options::IndexOptions::builder()
+            .unique(true)
+            .partial_filter_expression(doc! {"hash": {"$type": "string"}})
+            .build();
+        self.database()
+            .collection::<()>(Self::VIDEO_FILES)
+            .create_index(IndexModel::builder().keys(doc! {"hash": 1}).options(hash_options).build(), None)
+            .await?;
+
+        for (c, d) in [
+            (Self::SUBTITLES, doc! {"source.video": 1}),
+            (Self::SUBTITLES, doc! {"source.file": 1}),
+            (Self::VIDEOS, doc! {"name": "text", "description": "text", "tags": "text"}),
+            (Self::PLAYLISTS, doc! {"owner": 1}),
+            (Self::PLAYLISTS, doc! {"videos": 1}),
+            (Self::COMMENTS, doc! {"video": 1, "created": 1}),
+            (Self::HISTORY, doc! {"user": 1, "updated": -1}),
+            (Self::VIEW_STATS, doc! {"game": 1, "day": 1}),
+            (Self::SHARE_LINKS, doc! {"video": 1, "created": -1}),
+        ] {
+            self.database()
+                .collection::<()>(c)
+                .create_index(IndexModel::builder().keys(d).build(), None)
+                .await?;
+        }
+
+        // views outside the deduplication window are not needed anymore
+        let ttl_options = mongodb::options::IndexOptions::builder()
+            .expire_after(crate::video::analytics::VIEW_WINDOW.to_std().unwrap())
+            .build();
+        for c in [Self::RECENT_VIEWS, Self::SHARE_LINK_VIEWS] {
+            self.database()
+                .collection::<()>(c)
+                .create_index(IndexModel::builder().keys(doc! {"at": 1}).options(ttl_options.clone()).build(), None)
+                .await?;
+        }
+
+        // daily view counts are kept for the configured retention, checked when loading the config
+        let retention = CONFIG.analytics.retention.to_std().unwrap_or_default();
+        let ttl_options = mongodb::options::IndexOptions::builder()
+            .expire_after(retention)
+            .build();
+        let res = self.database()
+            .collection::<()>(Self::VIEW_STATS)
+            .create_index(IndexModel::builder().keys(doc! {"date": 1}).options(ttl_options).build(), None)
+            .await;
+        match res {
+            Ok(_) => Ok(()),
+            // the retention was changed since the index was created
+            Err(e) if error_code(&e) == Some(INDEX_OPTIONS_CONFLICT) => {
+                self.database()
+                    .run_command(doc! {
+                        "collMod": Self::VIEW_STATS,
+                        "index": {"keyPattern": {"date": 1}, "expireAfterSeconds": retention.as_secs() as i64},
+                    }, None)
+                    .await
+                    .map(|_| ())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // used by background tasks that live outside of a request
+    pub(crate) fn fetch<P: rocket::Phase>(rocket: &rocket::Rocket<P>) -> Option<Self> {
+        Db::fetch(rocket).map(|db| Self::new(db.0.clone()))
     }
 
     pub(crate) fn collection<T>(&self, name: &'static str) -> mongodb::Collection<T> {
@@ -51,12 +145,18 @@ impl DBWrapper {
         self.0.database(CONFIG.database.as_str())
     }
 
+    // indexes are created once at ignition, a failure aborts the launch
     pub(crate) async fn constraints_fairing(rocket: rocket::Rocket<Build>) -> rocket::fairing::Result {
         match Db::fetch(&rocket) {
             Some(db) => {
                 let db = DBWrapper::new(db.0.clone());
-                db._enforce_constraints().await;
-                Ok(rocket)
+                match db._enforce_constraints().await {
+                    Ok(()) => Ok(rocket),
+                    Err(e) => {
+                        log::error!("failed to create the database indexes: {}", e);
+                        Err(rocket)
+                    }
+                }
             }
             None => {
                 eprintln!("Failed to fetch database connection for constraints fairing");
@@ -87,9 +187,7 @@ impl<'r> FromRequest<'r> for DBWrapper {
     async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
         match request.guard::<Connection<Db>>().await {
             Outcome::Success(db) => {
-                let db = DBWrapper::new(db.into_inner());
-                db._enforce_constraints().await;
-                rocket::request::Outcome::Success(db)
+                rocket::request::Outcome::Success(DBWrapper::new(db.into_inner()))
             }
             Outcome::Error(_) => Self::Error::InternalServerError.outcome(),
             Outcome::Forward(f) => rocket::request::Outcome::Forward(f)
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use rocket::{futures::{StreamExt, TryStreamExt}, serde::json::Json};
+use rocket_db_pools::mongodb::{self, bson::{doc, Document, serde_helpers::chrono_datetime_as_bson_datetime}, options::UpdateOptions};
+use serde::{Deserialize, Serialize};
+
+use crate::{authentication::{AuthenticationError, UserGuard}, db::DBWrapper, response::{ApiErrorType, ApiResponder, ApiResponse}, user::User, video::{ListedVideo, Video}};
+
+// past this fraction of the duration a video counts as watched
+const COMPLETED_RATIO: f64 = 0.95;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Progress {
+    // seconds
+    pub(crate) position: f64,
+    pub(crate) completed: bool,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub(crate) updated: DateTime<Utc>,
+}
+
+impl ApiResponse for Progress {}
+
+// one entry per user and video, see the unique index in `DBWrapper::_enforce_constraints`
+#[derive(Serialize, Deserialize, Debug)]
+struct HistoryEntry {
+    user: String,
+    video: String,
+    #[serde(flatten)]
+    progress: Progress,
+}
+
+impl DBWrapper {
+    async fn set_progress(&self, user: &User, video: &str, progress: &Progress) -> Result<(), mongodb::error::Error> {
+        let entry = mongodb::bson::to_document(&HistoryEntry {
+            user: user.username.clone(),
+            video: video.to_string(),
+            progress: progress.clone(),
+        })?;
+        self
+            .collection::<HistoryEntry>(Self::HISTORY)
+            .update_one(
+                doc! {"user": &user.username, "video": video},
+                doc! {"$set": entry},
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await
+            .map(|_| ())
+    }
+
+    async fn get_progress(&self, user: &User, video: &str) -> Result<Option<Progress>, mongodb::error::Error> {
+        self
+            .collection::<HistoryEntry>(Self::HISTORY)
+            .find_one(doc! {"user": &user.username, "video": video}, None)
+            .await
+            .map(|e| e.map(|e| e.progress))
+    }
+
+    // progress of the user on each of the given videos, missing ones were never watched
+    pub(crate) async fn get_videos_progress(&self, user: &User, videos: &[String]) -> Result<HashMap<String, Progress>, mongodb::error::Error> {
+        self
+            .collection::<HistoryEntry>(Self::HISTORY)
+            .find(doc! {"user": &user.username, "video": {"$in": videos}}, None)
+            .await?
+            .map(|e| e.map(|e| (e.video, e.progress)))
+            .try_collect()
+            .await
+    }
+
+    pub(crate) async fn delete_video_history(&self, video: &str) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<HistoryEntry>(Self::HISTORY)
+            .delete_many(doc! {"video": video}, None)
+            .await
+            .map(|_| ())
+    }
+
+    // started but not finished videos, most recently watched first
+    async fn get_in_progress(&self, user: &User, limit: u32) -> Result<Vec<(Video, Progress)>, mongodb::error::Error> {
+        let entries: Vec<Document> = self
+            .collection::<HistoryEntry>(Self::HISTORY)
+            .aggregate(vec![
+                doc! {"$match": {"user": &user.username, "completed": false, "position": {"$gt": 0}}},
+                doc! {"$sort": {"updated": -1}},
+                // some of them could have become hidden, fetch a bit more than needed
+                doc! {"$limit": limit as i64 * 2},
+                doc! {"$lookup": {
+                    "from": Self::VIDEOS,
+                    "localField": "video",
+                    "foreignField": "_id",
+                    "as": "video",
+                }},
+                doc! {"$unwind": "$video"},
+            ], None)
+            .await?
+            .try_collect()
+            .await?;
+        let mut out = vec![];
+        for mut e in entries {
+            let video = mongodb::bson::from_document::<Video>(e.remove("video").unwrap().as_document().unwrap().clone()).unwrap();
+            let progress = mongodb::bson::from_document::<Progress>(e).unwrap();
+            if out.len() < limit as usize && video.user_authorized(Some(user), self).await? {
+                out.push((video, progress));
+            }
+        }
+        Ok(out)
+    }
+
+    // newest videos from the user's games that they haven't finished
+    async fn get_unwatched(&self, user: &User, skip: Option<u32>, limit: u32) -> Result<Vec<Video>, mongodb::error::Error> {
+        let games = self.get_user_games_ids(user).await?.into_iter().collect::<Vec<_>>();
+        let mut pipeline = vec![
+            doc! {"$match": {"game": {"$in": games}}},
+            doc! {"$lookup": {
+                "from": Self::HISTORY,
+                "let": {"video": "$_id"},
+                "pipeline": [
+                    {"$match": {"$expr": {"$and": [
+                        {"$eq": ["$video", "$$video"]},
+                        {"$eq": ["$user", &user.username]},
+                        {"$eq": ["$completed", true]},
+                    ]}}},
+                ],
+                "as": "_watched",
+            }},
+            doc! {"$match": {"_watched": {"$size": 0}}},
+            doc! {"$sort": {"added": -1, "_id": -1}},
+        ];
+        if let Some(skip) = skip {
+            pipeline.push(doc! {"$skip": skip as i64});
+        }
+        pipeline.push(doc! {"$limit": limit as i64});
+        pipeline.push(doc! {"$project": {"_watched": 0}});
+        self
+            .collection::<Document>(Self::VIDEOS)
+            .aggregate(pipeline, None)
+            .await?
+            .map(|d| d.map(|d| mongodb::bson::from_document::<Video>(d).unwrap()))
+            .try_collect()
+            .await
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum HistoryError {
+    VideoNotFound,
+    InvalidPosition,
+}
+
+impl ApiErrorType for HistoryError {
+    fn ty(&self) -> &'static str {
+        match self {
+            Self::VideoNotFound => "video_not_found",
+            Self::InvalidPosition => "invalid_position",
+        }
+    }
+
+    fn status(&self) -> rocket::http::Status {
+        match self {
+            Self::VideoNotFound => rocket::http::Status::NotFound,
+            Self::InvalidPosition => rocket::http::Status::BadRequest,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::VideoNotFound => "Video not found".to_string(),
+            Self::InvalidPosition => "Position is outside of the video".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ProgressForm {
+    position: f64,
+    // players can report the end explicitly, otherwise it's derived from the position
+    completed: Option<bool>,
+}
+
+// reported periodically by the player, under /video/<video>/progress
+#[post("/<video>/progress", data = "<form>", format = "json")]
+pub(crate) async fn report(video: &str, form: Json<ProgressForm>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<Progress> {
+    let user = user?.user;
+    let video = match db.get_video_resolved(video).await? {
+        Some(v) if v.user_authorized(Some(&user), &db).await? => v,
+        _ => return ApiResponder::Err(HistoryError::VideoNotFound.into()),
+    };
+    let duration = video.file.as_ref().unwrap_right().duration;
+    let position = form.position;
+    // allow some slack at the end, players can report slightly past the probed duration
+    if !position.is_finite() || position < 0. || duration.is_some_and(|d| position > d + 1.) {
+        return ApiResponder::Err(HistoryError::InvalidPosition.into());
+    }
+    let completed = form.completed.unwrap_or_else(|| duration.is_some_and(|d| position >= d * COMPLETED_RATIO));
+    let progress = Progress {
+        position,
+        completed,
+        updated: Utc::now(),
+    };
+    db.set_progress(&user, &video.id, &progress).await?;
+    progress.into()
+}
+
+#[get("/<video>/progress")]
+pub(crate) async fn get(video: &str, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<Progress> {
+    let user = user?.user;
+    match db.get_video(video).await? {
+        Some(v) if v.user_authorized(Some(&user), &db).await? => match db.get_progress(&user, &v.id).await? {
+            Some(p) => p.into(),
+            None => ApiResponder::Err(crate::response::ApiError::not_found()),
+        },
+        _ => ApiResponder::Err(HistoryError::VideoNotFound.into()),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(transparent)]
+pub(crate) struct ListResponse {
+    inner: Vec<ListedVideo>,
+}
+
+impl ApiResponse for ListResponse {}
+
+#[get("/continue?<limit>")]
+pub(crate) async fn continue_watching(limit: Option<u32>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<ListResponse> {
+    let user = user?.user;
+    let limit = limit.unwrap_or(20).min(50);
+    let inner = db
+        .get_in_progress(&user, limit)
+        .await?
+        .into_iter()
+        .map(|(video, progress)| ListedVideo::new(video, Some(progress)))
+        .collect();
+    ListResponse { inner }.into()
+}
+
+#[get("/unwatched?<limit>&<skip>")]
+pub(crate) async fn unwatched(limit: Option<u32>, skip: Option<u32>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<ListResponse> {
+    let user = user?.user;
+    let limit = limit.unwrap_or(50).min(50);
+    let videos = db.get_unwatched(&user, skip, limit).await?;
+    let inner = ListedVideo::with_progress(videos, &user, &db).await?;
+    ListResponse { inner }.into()
+}
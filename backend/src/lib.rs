@@ -14,6 +14,14 @@ mod game;
 mod cors;
 mod media;
 mod like;
+mod tools;
+mod cursor;
+mod playlist;
+mod comment;
+mod history;
+mod ranking;
+mod quota;
+mod storage;
 
 pub use config::CONFIG;
 pub use user::{User, Permissions};
@@ -36,7 +44,9 @@ pub fn options() {
 pub fn rocket() -> rocket::Rocket<rocket::Build> {
     // check if config are initialized
     config::CONFIG.check();
-    let build = rocket::build()
+    let figment = rocket::Config::figment();
+    let limits = video::custom_thumb::limits(figment.extract_inner("limits").unwrap_or_default());
+    let build = rocket::custom(figment.merge(("limits", limits)))
         .mount("/", routes![index])
         .mount("/api", routes![options])
         .mount("/api/auth", routes![
@@ -51,10 +61,23 @@ pub fn rocket() -> rocket::Rocket<rocket::Build> {
             user::delete,
             user::list,
             user::permissions,
+            quota::usage,
         ])
         .mount("/api/video", routes![
             video::upload,
+            video::tus::options,
+            video::tus::create,
+            video::tus::head,
+            video::tus::patch,
+            video::tus::terminate,
+            video::custom_thumb::from_timestamp,
+            video::custom_thumb::upload,
+            video::subtitle::list,
+            video::subtitle::upload,
+            video::subtitle::delete,
+            video::clip::create,
             video::list,
+            video::search,
             video::get,
             video::list_file,
             video::thumb,
@@ -65,6 +88,16 @@ pub fn rocket() -> rocket::Rocket<rocket::Build> {
             like::delete,
             like::video,
             like::video_single,
+            comment::list,
+            comment::add,
+            comment::edit,
+            comment::delete,
+            history::report,
+            history::get,
+            video::analytics::video,
+            video::share::create,
+            video::share::list,
+            video::share::revoke,
         ])
         .mount("/api/game", routes![
             game::add,
@@ -73,19 +106,57 @@ pub fn rocket() -> rocket::Rocket<rocket::Build> {
             game::add_user,
             game::remove_user,
             game::list_user_games,
+            video::analytics::game,
         ])
         .mount("/api/media", routes![
             media::serve_file,
+            media::hls_master,
+            media::hls_entry,
+            media::sprite_track,
+            media::sprite_image,
+            media::subtitle_track,
         ])
         .mount("/api/like", routes![
             like::user,
             like::user_single,
         ])
+        .mount("/api/ranking", routes![
+            ranking::top,
+            ranking::trending,
+            ranking::uploaders,
+        ])
+        .mount("/api/history", routes![
+            history::continue_watching,
+            history::unwatched,
+        ])
+        .mount("/api/job", routes![
+            video::job::list,
+            video::job::retry,
+            video::job::reprobe,
+        ])
+        .mount("/api/playlist", routes![
+            playlist::create,
+            playlist::list,
+            playlist::get,
+            playlist::update,
+            playlist::delete,
+            playlist::add_video,
+            playlist::remove_video,
+            playlist::reorder,
+        ])
         .mount("/share", routes![
             video::share::get,
+            video::share::link,
+            video::share::unlock,
+            video::embed::oembed,
+            playlist::share,
+            playlist::share_item,
         ])
         .attach(db::Db::init())
         .attach(AdHoc::try_on_ignite("MeTube db init", |rocket| async { db::DBWrapper::constraints_fairing(rocket).await }))
+        .attach(AdHoc::on_liftoff("MeTube config warnings", |_| Box::pin(async move { CONFIG.warn() })))
+        .attach(AdHoc::on_liftoff("MeTube job worker", |rocket| Box::pin(async move { video::job::spawn_worker(rocket) })))
+        .attach(AdHoc::on_liftoff("MeTube upload cleanup", |rocket| Box::pin(async move { video::tus::spawn_cleanup(rocket) })))
         .attach(cors::Cors);
 
     #[cfg(debug_assertions)]
@@ -1,10 +1,12 @@
 use std::collections::HashMap;
 use rocket::futures::{StreamExt, TryStreamExt};
 
-use rocket_db_pools::mongodb::{self, bson::doc};
+use rocket_db_pools::mongodb::{self, bson::{doc, Document}};
 use serde::{Deserialize, Serialize};
 
-use crate::{authentication::{AuthenticationError, UserGuard}, db::DBWrapper, response::{ApiErrorType, ApiResponder, ApiResponse}, user::User, video::Video};
+use crate::{authentication::{AuthenticationError, UserGuard}, cursor::{Cursor, NEXT_CURSOR_HEADER}, db::DBWrapper, response::{ApiErrorType, ApiResponder, ApiResponse}, user::User, video::Video};
+
+const LIKES_CURSOR: &str = "likes";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct Like {
@@ -26,8 +28,42 @@ impl DBWrapper {
             .await
     }
 
+    // liked videos, most recent first
+    pub(crate) async fn get_user_likes_page(&self, user: &User, skip: Option<u32>, limit: Option<u32>, cursor: Option<Cursor>) -> Result<(Vec<String>, Option<Cursor>), mongodb::error::Error> {
+        let mut m = doc! {"user": &user.username};
+        if let Some(ref c) = cursor {
+            m.insert("_id", doc! {"$lt": &c.id});
+        }
+        let mut pipeline = vec![
+            doc! {"$match": m},
+            doc! {"$sort": {"_id": -1}},
+        ];
+        if let Some(skip) = skip {
+            pipeline.push(doc! {"$skip": skip as i64});
+        }
+        if let Some(limit) = limit {
+            // one more to know whether there is a next page
+            pipeline.push(doc! {"$limit": limit as i64 + 1});
+        }
+        pipeline.push(doc! {"$project": {"video": 1}});
+        let mut docs: Vec<Document> = self
+            .collection::<()>(Self::LIKES)
+            .aggregate(pipeline, None)
+            .await?
+            .try_collect()
+            .await?;
+        let mut next = None;
+        if let Some(limit) = limit {
+            if docs.len() > limit as usize {
+                docs.truncate(limit as usize);
+                next = docs.last().and_then(|d| Cursor::after(LIKES_CURSOR, d, "_id"));
+            }
+        }
+        Ok((docs.iter().map(|d| d.get_str("video").unwrap().to_string()).collect(), next))
+    }
+
     pub(crate) async fn get_videos_likes(&self, user: &User) -> Result<HashMap<String, u16>, mongodb::error::Error> {
-        let videos = self.get_user_videos(user, false, None, None)
+        let videos = self.get_user_videos(user, None, None, None, None)
             .await?
             .1
             .into_iter()
@@ -55,10 +91,10 @@ impl DBWrapper {
 
     pub(crate) async fn add_like(&self, user: &User, video: &Video) -> Result<(), mongodb::error::Error> {
         self
-            .collection(Self::LIKES)
+            .collection::<Like>(Self::LIKES)
             .replace_one(
                 doc! {"user": &user.username, "video": &video.id},
-                doc! {"user": &user.username, "video": &video.id},
+                Like { user: user.username.clone(), video: video.id.clone() },
                 mongodb::options::ReplaceOptions::builder().upsert(true).build(),
             )
             .await
@@ -138,12 +174,24 @@ pub(crate) struct VideoList(Vec<String>);
 
 impl ApiResponse for VideoList {}
 
-// get likes that the user left
-#[get("/")]
-pub(crate) async fn user(user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<VideoList> {
+// get likes that the user left, all of them unless a limit or cursor is given
+#[get("/?<limit>&<skip>&<cursor>")]
+pub(crate) async fn user(limit: Option<u32>, skip: Option<u32>, cursor: Option<&str>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<VideoList> {
     let user = user?.user;
-    let likes = db.get_user_likes(&user).await?;
-    VideoList(likes).into()
+    if limit.is_none() && skip.is_none() && cursor.is_none() {
+        let likes = db.get_user_likes(&user).await?;
+        return VideoList(likes).into();
+    }
+    let cursor = match cursor.map(|c| Cursor::decode(c, LIKES_CURSOR)).transpose() {
+        Ok(c) => c,
+        Err(e) => return ApiResponder::Err(e.into()),
+    };
+    let limit = limit.unwrap_or(50).min(50);
+    let (likes, next) = db.get_user_likes_page(&user, skip, Some(limit), cursor).await?;
+    match next {
+        Some(next) => ApiResponder::OkWithHeaders(VideoList(likes), vec![(NEXT_CURSOR_HEADER, next.encode())]),
+        None => VideoList(likes).into(),
+    }
 }
 
 impl ApiResponse for bool {}
@@ -187,7 +235,7 @@ pub(crate) async fn video_single(video: &str, user: Result<UserGuard<()>, Authen
             if video.user_authorized(Some(&user), &db).await? {
                 let likes = db
                     .collection::<()>(DBWrapper::LIKES)
-                    .count_documents(doc! {"video": video.id, "user": user.username}, None)
+                    .count_documents(doc! {"video": video.id}, None)
                     .await?;
                 ApiResponder::Ok(likes as u16)
             } else {
@@ -1,60 +1,91 @@
-use std::io::SeekFrom;
-use rocket::{futures::Stream, http::ContentType, request::{FromRequest, Outcome}, response::{stream::{stream, ByteStream}, Responder}, serde::json::Json, tokio::io::{AsyncReadExt, AsyncSeekExt}, Response};
-use rocket::tokio::fs::File;
+use chrono::{DateTime, Timelike, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use rocket::{futures::{Stream, StreamExt}, http::{ContentType, HeaderMap, Status}, request::{FromRequest, Outcome}, response::{stream::{stream, ByteStream}, Responder}, Response};
 use serde::Serialize;
-use crate::{db::DBWrapper, response::{ApiError, ApiResponder}, video::Video, CONFIG};
+use rocket::fs::NamedFile;
+use std::net::IpAddr;
+use crate::{db::DBWrapper, response::{ApiError, ApiResponder}, storage::{ObjectInfo, STORAGE}, video::{analytics::{ViewSource, Viewer}, hls, sprite, subtitle, Video}, CONFIG};
 
+// past this many ranges, after coalescing, the header is ignored and the whole file is sent
+const MAX_RANGES: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+enum RangeSpec {
+    // `first-last` or `first-`, inclusive
+    From(u64, Option<u64>),
+    // `-n`: the last n bytes
+    Suffix(u64),
+}
+
+// a `Range: bytes=...` header, as in RFC 9110 section 14.2.
+//   absent, malformed or non bytes ranges forward, making `Option<Range>` none, so that the whole file is sent
 #[derive(Debug)]
 pub(crate) struct Range {
-    start: Option<u64>,
-    end: Option<u64>,
+    specs: Vec<RangeSpec>,
 }
 
-#[derive(Serialize)]
-#[serde(rename_all = "snake_case")]
-#[derive(Debug)]
-pub(crate) enum RangeError {
-    Format,
-    Start,
-    End,
+impl Range {
+    fn parse(header: &str) -> Option<Self> {
+        let (unit, set) = header.split_once('=')?;
+        if !unit.trim().eq_ignore_ascii_case("bytes") {
+            return None;
+        }
+        let specs = set
+            .split(',')
+            .map(str::trim)
+            // empty list elements are allowed
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let (first, last) = s.split_once('-')?;
+                let (first, last) = (first.trim(), last.trim());
+                if first.is_empty() {
+                    return Some(RangeSpec::Suffix(last.parse().ok()?));
+                }
+                let first = first.parse().ok()?;
+                if last.is_empty() {
+                    return Some(RangeSpec::From(first, None));
+                }
+                let last = last.parse().ok()?;
+                (last >= first).then_some(RangeSpec::From(first, Some(last)))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        (!specs.is_empty()).then_some(Self { specs })
+    }
+
+    // satisfiable ranges of a file of `len` bytes, sorted with overlapping and adjacent ones merged.
+    //   empty if none can be satisfied
+    fn resolve(&self, len: u64) -> Vec<(u64, u64)> {
+        let mut ranges = self.specs
+            .iter()
+            .filter_map(|s| match *s {
+                RangeSpec::From(first, _) if first >= len => None,
+                RangeSpec::From(first, last) => Some((first, last.map_or(len - 1, |l| l.min(len - 1)))),
+                RangeSpec::Suffix(0) => None,
+                RangeSpec::Suffix(_) if len == 0 => None,
+                RangeSpec::Suffix(n) => Some((len.saturating_sub(n), len - 1)),
+            })
+            .collect::<Vec<_>>();
+        ranges.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+        for (first, last) in ranges {
+            match merged.last_mut() {
+                Some(prev) if first <= prev.1.saturating_add(1) => prev.1 = prev.1.max(last),
+                _ => merged.push((first, last)),
+            }
+        }
+        merged
+    }
 }
 
 #[async_trait]
 impl<'r> FromRequest<'r> for Range {
-    type Error = RangeError;
+    type Error = ();
 
     async fn from_request(request: &'r rocket::request::Request<'_>) -> Outcome<Self, Self::Error> {
-        let head = request.headers().get_one("range").unwrap_or_default();
-
-        fn r(r: RangeError) -> Outcome<Range, RangeError> {
-            Outcome::Error((rocket::http::Status::BadRequest, r))
+        match request.headers().get_one("Range").and_then(Range::parse) {
+            Some(r) => Outcome::Success(r),
+            None => Outcome::Forward(Status::Ok),
         }
-
-        let head = head.split('=').collect::<Vec<&str>>();
-        if head.len() != 2 || head[0] != "bytes" {
-            return r(RangeError::Format);
-        }
-        let ends = head[1].split('-').collect::<Vec<&str>>();
-        if ends.len() != 2 {
-            return r(RangeError::Format);
-        }
-        let start = if ends[0].is_empty() {
-            None
-        } else {
-            match ends[0].parse::<u64>() {
-                Ok(s) => Some(s),
-                Err(_) => return r(RangeError::Start),
-            }
-        };
-        let end = if ends[1].is_empty() {
-            None
-        } else {
-            match ends[1].parse::<u64>() {
-                Ok(e) => Some(e),
-                Err(_) => return r(RangeError::End),
-            }
-        };
-        Outcome::Success(Range { start, end })
     }
 }
 
@@ -62,7 +93,9 @@ impl<'r> FromRequest<'r> for Range {
 #[serde(untagged)]
 pub(crate) enum StreamError {
     ApiError(ApiError),
-    RangeError(RangeError),
+    // none of the requested ranges overlap the file, holds its length
+    #[serde(skip)]
+    Unsatisfiable(u64),
     NotFound,
 }
 
@@ -74,15 +107,14 @@ impl From<std::io::Error> for ApiError {
 
 impl<'r, 'o: 'r> Responder<'r, 'o> for StreamError {
     fn respond_to(self, request: &'r rocket::Request) -> rocket::response::Result<'o> {
-        let mut res = rocket::response::Response::build();
         match self {
             Self::ApiError(e) => {
                 ApiResponder::Err::<()>(e).respond_to(request)
             }
-            Self::RangeError(e) => {
-                res
-                    .status(rocket::http::Status::BadRequest)
-                    .merge(Json(e).respond_to(request)?)
+            Self::Unsatisfiable(len) => {
+                Response::build()
+                    .status(Status::RangeNotSatisfiable)
+                    .raw_header("Content-Range", format!("bytes */{}", len))
                     .ok()
             }
             Self::NotFound => {
@@ -92,65 +124,178 @@ impl<'r, 'o: 'r> Responder<'r, 'o> for StreamError {
     }
 }
 
+// pieces of a response body, multipart responses interleave part headers with file ranges
+enum Segment {
+    Bytes(Vec<u8>),
+    // inclusive
+    File(u64, u64),
+}
+
+impl Segment {
+    fn len(&self) -> u64 {
+        match self {
+            Self::Bytes(b) => b.len() as u64,
+            Self::File(first, last) => last - first + 1,
+        }
+    }
+}
+
+// how long clients and proxies may keep media
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum CachePolicy {
+    // token urls die with the token, only the user's own cache may keep them until then
+    Token,
+    // public shares can be cached anywhere, for a short time since videos can be made private again
+    Public,
+    // share links and playlists can be revoked or depend on the user, always revalidated
+    Restricted,
+}
+
+impl CachePolicy {
+    fn header(&self) -> String {
+        match self {
+            Self::Token => format!("private, max-age={}", CONFIG.media_token_duration.num_seconds()),
+            Self::Public => "public, max-age=3600".to_string(),
+            Self::Restricted => "private, no-cache".to_string(),
+        }
+    }
+}
+
+// validators of a file served to clients, for conditional requests (RFC 9110 section 13)
+pub(crate) struct Validators {
+    // strong, already quoted
+    etag: String,
+    modified: Option<DateTime<Utc>>,
+}
+
+impl Validators {
+    // `id` identifies the content, the size and modification time change when the file is rewritten
+    pub(crate) fn new(id: &str, info: &ObjectInfo) -> Self {
+        let mtime = info.modified.map(|m| m.timestamp()).unwrap_or_default();
+        Self {
+            etag: format!("\"{}-{:x}-{:x}\"", id, info.size, mtime),
+            // http dates have a second precision
+            modified: info.modified.and_then(|m| m.with_nanosecond(0)),
+        }
+    }
+
+    fn http_date(d: &DateTime<Utc>) -> String {
+        d.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+    }
+
+    fn parse_http_date(s: &str) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc2822(s).ok().map(|d| d.with_timezone(&Utc))
+    }
+
+    // whether a cached copy is still valid, If-None-Match takes precedence over If-Modified-Since
+    pub(crate) fn not_modified(&self, headers: &HeaderMap<'_>) -> bool {
+        if let Some(inm) = headers.get_one("If-None-Match") {
+            // weak comparison
+            return inm
+                .split(',')
+                .map(|t| t.trim())
+                .any(|t| t == "*" || t.trim_start_matches("W/") == self.etag);
+        }
+        match (headers.get_one("If-Modified-Since").and_then(Self::parse_http_date), self.modified) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    // whether the Range header applies, otherwise the whole file is sent
+    fn range_applies(&self, headers: &HeaderMap<'_>) -> bool {
+        match headers.get_one("If-Range").map(str::trim) {
+            None => true,
+            // strong comparison, weak tags never match
+            Some(tag) if tag.starts_with('"') => tag == self.etag,
+            Some(date) => Self::parse_http_date(date).is_some_and(|d| Some(d) == self.modified),
+        }
+    }
+
+    pub(crate) fn apply(&self, res: &mut rocket::response::Builder<'_>) {
+        res.raw_header("ETag", self.etag.clone());
+        if let Some(ref m) = self.modified {
+            res.raw_header("Last-Modified", Self::http_date(m));
+        }
+    }
+
+    // empty 304 response, carrying the headers the full one would have had
+    pub(crate) fn not_modified_response<'r>(&self, cache: &str) -> rocket::response::Result<'r> {
+        let mut res = Response::build();
+        res.status(Status::NotModified);
+        self.apply(&mut res);
+        res.raw_header("Cache-Control", cache.to_string());
+        res.ok()
+    }
+}
+
 pub(crate) struct MediaStream {
     range: Option<Range>,
     len: u64,
-    file: rocket::tokio::fs::File,
+    key: String,
     name: String,
+    validators: Validators,
+    cache: CachePolicy,
 }
 
-// impl<S> MediaStream<S> {
-//     fn stream_gen(self) -> ByteStream<impl Stream<Item = Vec<u8>>> {
-//         ByteStream::from(self.stream)
-//     }
-// }
-
 impl MediaStream {
-    pub(crate) async fn from_video(range: Option<Range>, video: Video) -> Result<Self, StreamError> {
+    pub(crate) async fn from_video(range: Option<Range>, video: Video, cache: CachePolicy) -> Result<Self, StreamError> {
         let name = video.download_name();
-        let file = File::open(video.file.unwrap_right().path()).await.map_err(|e| StreamError::ApiError(e.into()))?; 
-        let flen = file.metadata().await.map(|m| m.len()).map_err(|e| StreamError::ApiError(e.into()))?;
-        if let Some(ref range) = range {
-            if let Some(ref start) = range.start {
-                if *start >= flen {
-                    return Err(StreamError::RangeError(RangeError::Start));
-                }
-            }
-            if let Some(ref end) = range.end {
-                if *end >= flen {
-                    return Err(StreamError::RangeError(RangeError::End));
-                }
-            }
-        }
+        let video_file = video.file.unwrap_right();
+        let info = STORAGE
+            .stat(video_file.key())
+            .await
+            .map_err(|e| StreamError::ApiError(e.into()))?
+            .ok_or(StreamError::NotFound)?;
         Ok(Self {
             range,
-            len: flen,
-            file,
+            len: info.size,
+            key: video_file.key().to_string(),
             name,
+            validators: Validators::new(&video_file.id, &info),
+            cache,
         })
     }
 
-    fn gen_stream(mut self) -> ByteStream<impl Stream<Item = Vec<u8>>> {
-        let (mut pos, end) = match self.range {
-            Some(r) => (r.start.unwrap_or(0u64), r.end.unwrap_or(self.len - 1) + 1),
-            None => (0u64, self.len),
-        };
-        let mut buf = vec![0; CONFIG.media_chunk as usize];
+    // body of a `multipart/byteranges` response
+    fn multipart(&self, ranges: &[(u64, u64)], boundary: &str, ty: &ContentType) -> Vec<Segment> {
+        let mut segments = vec![];
+        for (i, &(first, last)) in ranges.iter().enumerate() {
+            let lead = if i == 0 { "" } else { "\r\n" };
+            segments.push(Segment::Bytes(format!(
+                "{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                lead, boundary, ty, first, last, self.len,
+            ).into_bytes()));
+            segments.push(Segment::File(first, last));
+        }
+        segments.push(Segment::Bytes(format!("\r\n--{}--\r\n", boundary).into_bytes()));
+        segments
+    }
+
+    // the status is already sent when reading fails, so the body is just cut short
+    fn gen_stream(self, segments: Vec<Segment>) -> ByteStream<impl Stream<Item = Vec<u8>>> {
         ByteStream::from(stream! {
-            // use default chunk size:
-            if pos > 0 {
-                self.file.seek(SeekFrom::Start(pos)).await.unwrap();
-            }
-            loop {
-                if pos + CONFIG.media_chunk > end {
-                    let mut last_buf = vec![0; (end - pos) as usize];
-                    self.file.read_exact(&mut last_buf).await.unwrap();
-                    yield last_buf;
-                    break;
-                } else {
-                    pos += CONFIG.media_chunk;
-                    self.file.read_exact(&mut buf).await.unwrap();
-                    yield buf.clone();
+            for segment in segments {
+                match segment {
+                    Segment::Bytes(b) => yield b,
+                    Segment::File(first, last) => {
+                        let mut chunks = match STORAGE.get_range(&self.key, first, last).await {
+                            Ok(c) => c,
+                            Err(e) => {
+                                error!("error while reading {}: {}", self.key, e);
+                                break;
+                            }
+                        };
+                        while let Some(chunk) = chunks.next().await {
+                            match chunk {
+                                Ok(c) => yield c,
+                                Err(e) => {
+                                    error!("error while reading {}: {}", self.key, e);
+                                    return;
+                                }
+                            }
+                        }
+                    }
                 }
             }
         })
@@ -158,42 +303,64 @@ impl MediaStream {
 }
 
 impl<'r> Responder<'r, 'r> for MediaStream {
-    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'r> {
-        let mut res = Response::build();
-        res
-            .header(rocket::http::Header::new("Accept-Ranges", "bytes"));
-
-        let ty = ContentType::from_extension(self.name.split('.').next_back().unwrap_or_default()).unwrap_or(ContentType::Binary);
-        let length = match self.range {
-            Some(ref r) => {
-                res
-                    .header(rocket::http::Header::new("Content-Range", format!("bytes {}-{}/{}", r.start.unwrap_or(0), r.end.unwrap_or(self.len - 1), self.len)));
-                r.end.unwrap_or(self.len - 1) - r.start.unwrap_or(0) + 1
-            }
-            None => self.len,
+    fn respond_to(mut self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'r> {
+        let cache = self.cache.header();
+        if self.validators.not_modified(request.headers()) {
+            return self.validators.not_modified_response(&cache);
+        }
+        // satisfiable ranges, none when the whole file is sent
+        let ranges = match self.range.take().filter(|_| self.validators.range_applies(request.headers())) {
+            Some(r) => match r.resolve(self.len) {
+                ranges if ranges.is_empty() => return StreamError::Unsatisfiable(self.len).respond_to(request),
+                ranges if ranges.len() > MAX_RANGES => vec![],
+                ranges => ranges,
+            },
+            None => vec![],
         };
-        let status = if self.range.is_some() {
-            rocket::http::Status::PartialContent
-        } else {
-            rocket::http::Status::Ok
+
+        let mut ty = ContentType::from_extension(self.name.split('.').next_back().unwrap_or_default()).unwrap_or(ContentType::Binary);
+        let mut content_range = None;
+        let (status, segments) = match ranges.as_slice() {
+            [] if self.len == 0 => (Status::Ok, vec![]),
+            [] => (Status::Ok, vec![Segment::File(0, self.len - 1)]),
+            &[(first, last)] => {
+                content_range = Some(format!("bytes {}-{}/{}", first, last, self.len));
+                (Status::PartialContent, vec![Segment::File(first, last)])
+            }
+            _ => {
+                let boundary = rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(32)
+                    .map(char::from)
+                    .collect::<String>();
+                let segments = self.multipart(&ranges, &boundary, &ty);
+                ty = ContentType::new("multipart", "byteranges").with_params(("boundary", boundary));
+                (Status::PartialContent, segments)
+            }
         };
+        let length = segments.iter().map(Segment::len).sum::<u64>();
+
+        let mut res = Response::build();
         res
+            .header(rocket::http::Header::new("Accept-Ranges", "bytes"))
             .header(rocket::http::Header::new("Content-Disposition", format!("inline; filename=\"{}\"", self.name)))
-            .merge(self.gen_stream().respond_to(request)?)
+            .raw_header("Cache-Control", cache);
+        self.validators.apply(&mut res);
+        res
+            .merge(self.gen_stream(segments).respond_to(request)?)
             .header(rocket::http::Header::new("Content-Length", length.to_string()))
             .status(status)
-            .header(ty)
-            .ok()
+            .header(ty);
+        if let Some(r) = content_range {
+            res.header(rocket::http::Header::new("Content-Range", r));
+        }
+        res.ok()
     }
 }
 
 
-#[get("/<token>")]
-    pub async fn serve_file(
-        token: &str,
-        range: Option<Range>, 
-        db: DBWrapper
-    ) -> Result<MediaStream, StreamError> {
+// resolves a media token into the video it grants access to
+async fn token_video(token: &str, db: &DBWrapper) -> Result<Video, StreamError> {
     let t = match db.get_video_token(token).await.map_err(|e| StreamError::ApiError(e.into()))? {
         Some(t) => if !t.token.valid(token) {
             warn!("token is invalid: {:?}", t);
@@ -201,10 +368,248 @@ impl<'r> Responder<'r, 'r> for MediaStream {
         } else { t },
         None => return Err(StreamError::NotFound),
     };
-    let mut video = db.get_video_resolved(&t.video).await.map_err(|e| StreamError::ApiError(e.into()))?.ok_or(StreamError::NotFound)?;
+    db.get_video_resolved(&t.video).await.map_err(|e| StreamError::ApiError(e.into()))?.ok_or(StreamError::NotFound)
+}
+
+#[get("/<token>")]
+    pub async fn serve_file(
+        token: &str,
+        range: Option<Range>, 
+        ip: Option<IpAddr>,
+        db: DBWrapper
+    ) -> Result<MediaStream, StreamError> {
+    let mut video = token_video(token, &db).await?;
+    // players issue many range requests per playback, only the first one of a token is a view
+    if let Some(t) = db.use_video_token(token).await.map_err(|e| StreamError::ApiError(e.into()))? {
+        let viewer = match t.user {
+            Some(ref u) => Viewer::User(u),
+            None => Viewer::Address(ip),
+        };
+        db.record_view(&video, viewer, ViewSource::InApp).await;
+    }
     // resolve eventual converted video
     video.resolve_converted(&db).await.map_err(|e| StreamError::ApiError(e.into()))?;
 
-    MediaStream::from_video(range, video).await
+    MediaStream::from_video(range, video, CachePolicy::Token).await
+}
+
+fn mpegurl() -> ContentType {
+    ContentType::new("application", "vnd.apple.mpegurl")
+}
+
+#[get("/<token>/hls/master.m3u8")]
+pub(crate) async fn hls_master(token: &str, db: DBWrapper) -> Result<(ContentType, String), StreamError> {
+    let video = token_video(token, &db).await?;
+    match video.file.unwrap_right().hls {
+        Some(renditions) => Ok((mpegurl(), hls::master_playlist(&renditions))),
+        None => Err(StreamError::NotFound),
+    }
+}
+
+#[get("/<token>/hls/<rendition>/<entry>")]
+pub(crate) async fn hls_entry(token: &str, rendition: &str, entry: &str, db: DBWrapper) -> Result<(ContentType, NamedFile), StreamError> {
+    let video = token_video(token, &db).await?;
+    let file = video.file.unwrap_right();
+    let available = file.hls
+        .as_ref()
+        .is_some_and(|r| r.iter().any(|r| r.name == rendition));
+    if !available || !hls::valid_entry(entry) {
+        return Err(StreamError::NotFound);
+    }
+    let ty = if entry == hls::PLAYLIST {
+        mpegurl()
+    } else {
+        ContentType::new("video", "mp2t")
+    };
+    match NamedFile::open(hls::dir(&file.id).join(rendition).join(entry)).await {
+        Ok(f) => Ok((ty, f)),
+        Err(_) => Err(StreamError::NotFound),
+    }
+}
+
+#[get("/<token>/sprite.vtt")]
+pub(crate) async fn sprite_track(token: &str, db: DBWrapper) -> Result<(ContentType, String), StreamError> {
+    let video = token_video(token, &db).await?;
+    let file = video.file.unwrap_right();
+    match (file.sprite, file.duration) {
+        (Some(sheet), Some(duration)) => Ok((ContentType::new("text", "vtt"), sheet.webvtt(duration))),
+        _ => Err(StreamError::NotFound),
+    }
+}
+
+#[get("/<token>/sprite.jpg")]
+pub(crate) async fn sprite_image(token: &str, db: DBWrapper) -> Result<NamedFile, StreamError> {
+    let video = token_video(token, &db).await?;
+    let file = video.file.unwrap_right();
+    if file.sprite.is_none() {
+        return Err(StreamError::NotFound);
+    }
+    NamedFile::open(sprite::path(&file.id)).await.map_err(|_| StreamError::NotFound)
+}
+
+#[get("/<token>/subtitles/<id>")]
+pub(crate) async fn subtitle_track(token: &str, id: &str, db: DBWrapper) -> Result<(ContentType, NamedFile), StreamError> {
+    let video = token_video(token, &db).await?;
+    let file = video.file.unwrap_right();
+    // only tracks attached to the token's video can be served
+    let id = id.strip_suffix(".vtt").unwrap_or(id);
+    let subs = db.get_subtitles(&video.id, &file.id).await.map_err(|e| StreamError::ApiError(e.into()))?;
+    if !subs.iter().any(|s| s.id == id) {
+        return Err(StreamError::NotFound);
+    }
+    match NamedFile::open(subtitle::path(id)).await {
+        Ok(f) => Ok((ContentType::new("text", "vtt"), f)),
+        Err(_) => Err(StreamError::NotFound),
+    }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(header: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+        Range::parse(header).map(|r| r.resolve(len))
+    }
+
+    #[test]
+    fn parse_malformed() {
+        assert!(Range::parse("bytes").is_none());
+        assert!(Range::parse("items=0-1").is_none());
+        assert!(Range::parse("bytes=").is_none());
+        assert!(Range::parse("bytes=,").is_none());
+        assert!(Range::parse("bytes=a-b").is_none());
+        assert!(Range::parse("bytes=1").is_none());
+        // a single invalid range discards the whole header
+        assert!(Range::parse("bytes=0-1,x").is_none());
+        // last before first is invalid, not unsatisfiable
+        assert!(Range::parse("bytes=5-2").is_none());
+        assert!(Range::parse("BYTES = 0-1, ,").is_some());
+    }
+
+    #[test]
+    fn resolve_single() {
+        assert_eq!(resolve("bytes=0-0", 10), Some(vec![(0, 0)]));
+        assert_eq!(resolve("bytes=2-5", 10), Some(vec![(2, 5)]));
+        assert_eq!(resolve("bytes=2-", 10), Some(vec![(2, 9)]));
+        assert_eq!(resolve("bytes=-3", 10), Some(vec![(7, 9)]));
+        // clamped to the end of the file
+        assert_eq!(resolve("bytes=5-100", 10), Some(vec![(5, 9)]));
+        assert_eq!(resolve("bytes=-100", 10), Some(vec![(0, 9)]));
+    }
+
+    #[test]
+    fn resolve_unsatisfiable() {
+        assert_eq!(resolve("bytes=-0", 10), Some(vec![]));
+        assert_eq!(resolve("bytes=10-", 10), Some(vec![]));
+        assert_eq!(resolve("bytes=20-30", 10), Some(vec![]));
+        // only the satisfiable ones are kept
+        assert_eq!(resolve("bytes=20-30,0-1", 10), Some(vec![(0, 1)]));
+    }
+
+    #[test]
+    fn resolve_empty_file() {
+        assert_eq!(resolve("bytes=0-", 0), Some(vec![]));
+        assert_eq!(resolve("bytes=0-0", 0), Some(vec![]));
+        assert_eq!(resolve("bytes=-5", 0), Some(vec![]));
+        assert_eq!(resolve("bytes=-0", 0), Some(vec![]));
+    }
+
+    #[test]
+    fn resolve_merges() {
+        // overlapping
+        assert_eq!(resolve("bytes=0-5,3-8", 10), Some(vec![(0, 8)]));
+        assert_eq!(resolve("bytes=0-9,2-3", 10), Some(vec![(0, 9)]));
+        assert_eq!(resolve("bytes=-2,7-", 10), Some(vec![(7, 9)]));
+        // adjacent
+        assert_eq!(resolve("bytes=0-4,5-9", 10), Some(vec![(0, 9)]));
+        // sorted, with a gap kept apart
+        assert_eq!(resolve("bytes=6-7,0-1,2-3", 10), Some(vec![(0, 3), (6, 7)]));
+        assert_eq!(resolve("bytes=0-1,3-4", 10), Some(vec![(0, 1), (3, 4)]));
+    }
+
+    #[test]
+    fn resolve_many() {
+        // disjoint ranges above the limit are all returned, the responder then sends the whole file
+        let disjoint = (0..=MAX_RANGES as u64).map(|i| format!("{}-{}", i * 2, i * 2)).collect::<Vec<_>>().join(",");
+        assert_eq!(resolve(&format!("bytes={}", disjoint), 100).unwrap().len(), MAX_RANGES + 1);
+        // the limit counts ranges after coalescing
+        let adjacent = (0..=MAX_RANGES as u64).map(|i| format!("{}-{}", i, i)).collect::<Vec<_>>().join(",");
+        assert_eq!(resolve(&format!("bytes={}", adjacent), 100), Some(vec![(0, MAX_RANGES as u64)]));
+    }
+
+    fn validators() -> Validators {
+        let modified = DateTime::parse_from_rfc3339("2024-05-01T12:00:00.250Z").unwrap().with_timezone(&Utc);
+        Validators::new("abc", &ObjectInfo { size: 10, modified: Some(modified) })
+    }
+
+    fn headers<'h>(pairs: &[(&'static str, &'h str)]) -> HeaderMap<'h> {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            headers.add_raw(name, value);
+        }
+        headers
+    }
+
+    const MODIFIED: &str = "Wed, 01 May 2024 12:00:00 GMT";
+    const BEFORE: &str = "Wed, 01 May 2024 11:59:59 GMT";
+    const AFTER: &str = "Wed, 01 May 2024 12:00:01 GMT";
+
+    #[test]
+    fn validators_from_info() {
+        let v = validators();
+        assert_eq!(v.etag, format!("\"abc-a-{:x}\"", 1714564800));
+        // truncated to the second, as sent in Last-Modified
+        assert_eq!(v.modified.map(|m| Validators::http_date(&m)).as_deref(), Some(MODIFIED));
+    }
+
+    #[test]
+    fn if_none_match() {
+        let v = validators();
+        let etag = v.etag.as_str();
+        assert!(v.not_modified(&headers(&[("If-None-Match", etag)])));
+        assert!(v.not_modified(&headers(&[("If-None-Match", "*")])));
+        assert!(v.not_modified(&headers(&[("If-None-Match", "\"other\", *")])));
+        // weak comparison, the W/ prefix is ignored
+        let weak = format!("\"other\", W/{}", etag);
+        assert!(v.not_modified(&headers(&[("If-None-Match", &weak)])));
+        assert!(!v.not_modified(&headers(&[("If-None-Match", "\"other\"")])));
+        assert!(!v.not_modified(&headers(&[])));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let v = validators();
+        assert!(v.not_modified(&headers(&[("If-Modified-Since", MODIFIED)])));
+        assert!(v.not_modified(&headers(&[("If-Modified-Since", AFTER)])));
+        assert!(!v.not_modified(&headers(&[("If-Modified-Since", BEFORE)])));
+        assert!(!v.not_modified(&headers(&[("If-Modified-Since", "yesterday")])));
+        // without a modification time the date can't be compared
+        let unknown = Validators::new("abc", &ObjectInfo { size: 10, modified: None });
+        assert!(!unknown.not_modified(&headers(&[("If-Modified-Since", AFTER)])));
+    }
+
+    #[test]
+    fn if_none_match_precedence() {
+        let v = validators();
+        // a mismatching tag wins over a matching date
+        assert!(!v.not_modified(&headers(&[("If-None-Match", "\"other\""), ("If-Modified-Since", AFTER)])));
+        // and a matching tag over a mismatching date
+        assert!(v.not_modified(&headers(&[("If-None-Match", "*"), ("If-Modified-Since", BEFORE)])));
+    }
+
+    #[test]
+    fn if_range() {
+        let v = validators();
+        let etag = v.etag.as_str();
+        assert!(v.range_applies(&headers(&[])));
+        assert!(v.range_applies(&headers(&[("If-Range", etag)])));
+        assert!(!v.range_applies(&headers(&[("If-Range", "\"other\"")])));
+        // strong comparison, weak tags never match
+        let weak = format!("W/{}", etag);
+        assert!(!v.range_applies(&headers(&[("If-Range", &weak)])));
+        // dates must match exactly
+        assert!(v.range_applies(&headers(&[("If-Range", MODIFIED)])));
+        assert!(!v.range_applies(&headers(&[("If-Range", AFTER)])));
+    }
+}
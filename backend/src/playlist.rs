@@ -0,0 +1,447 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+use rocket::{futures::TryStreamExt, serde::json::Json};
+use rocket_db_pools::mongodb::{self, bson::{doc, oid::ObjectId, serde_helpers::chrono_datetime_as_bson_datetime}};
+use serde::{Deserialize, Serialize};
+
+use crate::{authentication::{AuthenticationError, UserGuard}, db::DBWrapper, media::{CachePolicy, MediaStream, Range}, response::{ApiErrorType, ApiResponder, ApiResponse}, user::{Permissions, User}, video::{analytics::{ViewSource, Viewer}, share::ShareResponder, Video}};
+
+const MAX_VIDEOS: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub(crate) enum Visibility {
+    Private,
+    // visible to the members of a game
+    Game { game: String },
+    Public,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Playlist {
+    #[serde(rename = "_id")]
+    pub id: String,
+    name: String,
+    description: Option<String>,
+    owner: String,
+    visibility: Visibility,
+    // ordered video ids
+    videos: Vec<String>,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    created: DateTime<Utc>,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    updated: DateTime<Utc>,
+}
+
+impl ApiResponse for Playlist {}
+
+impl Playlist {
+    // anonymous users can only see public playlists
+    async fn user_authorized(&self, user: Option<&User>, db: &DBWrapper) -> Result<bool, mongodb::error::Error> {
+        Ok(match (&self.visibility, user) {
+            (Visibility::Public, _) => true,
+            (_, None) => false,
+            (_, Some(user)) if user.username == self.owner || user.allowed(Permissions::ADMIN) => true,
+            (Visibility::Game { game }, Some(user)) => db.is_user_in_game(game, &user.username).await?,
+            (Visibility::Private, _) => false,
+        })
+    }
+
+    fn user_can_modify(&self, user: &User) -> bool {
+        self.owner == user.username || user.allowed(Permissions::ADMIN)
+    }
+
+    // the playlist videos that the user can watch, in playlist order
+    async fn visible_videos(&self, user: Option<&User>, db: &DBWrapper) -> Result<Vec<Video>, mongodb::error::Error> {
+        // an empty id list would match every video
+        if self.videos.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut videos = db
+            .get_videos(self.videos.clone())
+            .await?
+            .into_iter()
+            .map(|v| (v.id.clone(), v))
+            .collect::<HashMap<_, _>>();
+        // fetched once, the playlist can hold many videos
+        let user_games = match user {
+            Some(u) => db.get_user_games_ids(u).await?,
+            None => HashSet::new(),
+        };
+        Ok(self.videos
+            .iter()
+            .filter_map(|id| videos.remove(id))
+            .filter(|v| v.user_authorized_in(user, &user_games))
+            .collect())
+    }
+}
+
+impl DBWrapper {
+    async fn get_playlist(&self, id: &str) -> Result<Option<Playlist>, mongodb::error::Error> {
+        self
+            .collection::<Playlist>(Self::PLAYLISTS)
+            .find_one(doc! {"_id": id}, None)
+            .await
+    }
+
+    // playlists owned by the user or shared with one of their games
+    async fn get_user_playlists(&self, user: &User) -> Result<Vec<Playlist>, mongodb::error::Error> {
+        let games = self.get_user_games_ids(user).await?.into_iter().collect::<Vec<_>>();
+        self
+            .collection::<Playlist>(Self::PLAYLISTS)
+            .find(
+                doc! {"$or": [
+                    {"owner": &user.username},
+                    {"visibility.type": "game", "visibility.game": {"$in": games}},
+                ]},
+                mongodb::options::FindOptions::builder().sort(doc! {"updated": -1}).build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    async fn save_playlist(&self, playlist: &Playlist) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<Playlist>(Self::PLAYLISTS)
+            .replace_one(doc! {"_id": &playlist.id}, playlist, mongodb::options::ReplaceOptions::builder().upsert(true).build())
+            .await
+            .map(|_| ())
+    }
+
+    // removes a deleted video from every playlist
+    pub(crate) async fn remove_video_from_playlists(&self, video: &str) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<Playlist>(Self::PLAYLISTS)
+            .update_many(doc! {"videos": video}, doc! {"$pull": {"videos": video}}, None)
+            .await
+            .map(|_| ())
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum PlaylistError {
+    PlaylistNotFound,
+    VideoNotFound,
+    GameNotFound,
+    InvalidName,
+    InvalidOrder,
+    TooManyVideos,
+}
+
+impl ApiErrorType for PlaylistError {
+    fn ty(&self) -> &'static str {
+        match self {
+            Self::PlaylistNotFound => "playlist_not_found",
+            Self::VideoNotFound => "video_not_found",
+            Self::GameNotFound => "game_not_found",
+            Self::InvalidName => "invalid_name",
+            Self::InvalidOrder => "invalid_order",
+            Self::TooManyVideos => "too_many_videos",
+        }
+    }
+
+    fn status(&self) -> rocket::http::Status {
+        match self {
+            Self::PlaylistNotFound => rocket::http::Status::NotFound,
+            Self::VideoNotFound => rocket::http::Status::NotFound,
+            Self::GameNotFound => rocket::http::Status::NotFound,
+            Self::InvalidName => rocket::http::Status::BadRequest,
+            Self::InvalidOrder => rocket::http::Status::BadRequest,
+            Self::TooManyVideos => rocket::http::Status::BadRequest,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::PlaylistNotFound => "Playlist not found".to_string(),
+            Self::VideoNotFound => "Video not found".to_string(),
+            Self::GameNotFound => "Game not found".to_string(),
+            Self::InvalidName => "Playlist name can't be empty".to_string(),
+            Self::InvalidOrder => "New order must contain exactly the videos of the playlist".to_string(),
+            Self::TooManyVideos => format!("Playlists can't contain more than {} videos", MAX_VIDEOS),
+        }
+    }
+}
+
+// fetches a playlist the user is allowed to change
+async fn modifiable_playlist(id: &str, user: &User, db: &DBWrapper) -> Result<Playlist, crate::response::ApiError> {
+    match db.get_playlist(id).await? {
+        Some(p) if p.user_can_modify(user) => Ok(p),
+        // don't disclose playlists the user can't see
+        Some(p) if p.user_authorized(Some(user), db).await? => Err(AuthenticationError::InsufficientPermissions(Permissions::ADMIN).into()),
+        _ => Err(PlaylistError::PlaylistNotFound.into()),
+    }
+}
+
+// sharing with a game requires being part of it
+async fn check_visibility(visibility: &Visibility, user: &User, db: &DBWrapper) -> Result<(), crate::response::ApiError> {
+    if let Visibility::Game { game } = visibility {
+        if !db.get_user_games_ids(user).await?.contains(game) {
+            return Err(PlaylistError::GameNotFound.into());
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateForm {
+    name: String,
+    description: Option<String>,
+    visibility: Visibility,
+    #[serde(default)]
+    videos: Vec<String>,
+}
+
+#[post("/", data = "<form>", format = "json")]
+pub(crate) async fn create(form: Json<CreateForm>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<Playlist> {
+    let user = user?.user;
+    let form = form.into_inner();
+    if form.name.trim().is_empty() {
+        return ApiResponder::Err(PlaylistError::InvalidName.into());
+    }
+    if form.videos.len() > MAX_VIDEOS {
+        return ApiResponder::Err(PlaylistError::TooManyVideos.into());
+    }
+    if let Err(e) = check_visibility(&form.visibility, &user, &db).await {
+        return ApiResponder::Err(e);
+    }
+    let mut videos: Vec<String> = vec![];
+    for id in form.videos {
+        match db.get_video(&id).await? {
+            Some(v) if v.user_authorized(Some(&user), &db).await? => {
+                if !videos.contains(&v.id) {
+                    videos.push(v.id);
+                }
+            }
+            _ => return ApiResponder::Err(PlaylistError::VideoNotFound.into()),
+        }
+    }
+    let now = Utc::now();
+    let playlist = Playlist {
+        id: ObjectId::new().to_hex(),
+        name: form.name.trim().to_string(),
+        description: form.description,
+        owner: user.username,
+        visibility: form.visibility,
+        videos,
+        created: now,
+        updated: now,
+    };
+    db.save_playlist(&playlist).await?;
+    playlist.into()
+}
+
+#[derive(Serialize)]
+#[serde(transparent)]
+pub(crate) struct ListResponse {
+    inner: Vec<Playlist>,
+}
+
+impl ApiResponse for ListResponse {}
+
+#[get("/")]
+pub(crate) async fn list(user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<ListResponse> {
+    let user = user?.user;
+    ListResponse { inner: db.get_user_playlists(&user).await? }.into()
+}
+
+#[derive(Serialize)]
+pub(crate) struct PlaylistResponse {
+    // `videos` only holds the ids of the items, hidden videos aren't disclosed
+    #[serde(flatten)]
+    playlist: Playlist,
+    // only the videos the user can watch
+    items: Vec<Video>,
+}
+
+impl PlaylistResponse {
+    fn new(mut playlist: Playlist, items: Vec<Video>) -> Self {
+        playlist.videos = items.iter().map(|v| v.id.clone()).collect();
+        Self { playlist, items }
+    }
+}
+
+impl ApiResponse for PlaylistResponse {}
+
+#[get("/<id>")]
+pub(crate) async fn get(id: &str, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<PlaylistResponse> {
+    let user = user.ok().map(|u| u.user);
+    match db.get_playlist(id).await? {
+        Some(playlist) if playlist.user_authorized(user.as_ref(), &db).await? => {
+            let items = playlist.visible_videos(user.as_ref(), &db).await?;
+            PlaylistResponse::new(playlist, items).into()
+        }
+        _ => ApiResponder::Err(PlaylistError::PlaylistNotFound.into()),
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UpdateForm {
+    name: Option<String>,
+    // an empty description removes it
+    description: Option<String>,
+    visibility: Option<Visibility>,
+}
+
+#[post("/<id>", data = "<form>", format = "json")]
+pub(crate) async fn update(id: &str, form: Json<UpdateForm>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<Playlist> {
+    let user = user?.user;
+    let mut playlist = match modifiable_playlist(id, &user, &db).await {
+        Ok(p) => p,
+        Err(e) => return ApiResponder::Err(e),
+    };
+    let UpdateForm { name, description, visibility } = form.into_inner();
+    if let Some(name) = name {
+        if name.trim().is_empty() {
+            return ApiResponder::Err(PlaylistError::InvalidName.into());
+        }
+        playlist.name = name.trim().to_string();
+    }
+    if let Some(description) = description {
+        playlist.description = (!description.trim().is_empty()).then_some(description);
+    }
+    if let Some(visibility) = visibility {
+        if let Err(e) = check_visibility(&visibility, &user, &db).await {
+            return ApiResponder::Err(e);
+        }
+        playlist.visibility = visibility;
+    }
+    playlist.updated = Utc::now();
+    db.save_playlist(&playlist).await?;
+    playlist.into()
+}
+
+#[delete("/<id>")]
+pub(crate) async fn delete(id: &str, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<()> {
+    let user = user?.user;
+    let playlist = match modifiable_playlist(id, &user, &db).await {
+        Ok(p) => p,
+        Err(e) => return ApiResponder::Err(e),
+    };
+    db
+        .collection::<Playlist>(DBWrapper::PLAYLISTS)
+        .delete_one(doc! {"_id": &playlist.id}, None)
+        .await?;
+    ApiResponder::Ok(())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AddForm {
+    video: String,
+    // appended when missing or past the end
+    position: Option<usize>,
+}
+
+#[post("/<id>/videos", data = "<form>", format = "json")]
+pub(crate) async fn add_video(id: &str, form: Json<AddForm>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<Playlist> {
+    let user = user?.user;
+    let mut playlist = match modifiable_playlist(id, &user, &db).await {
+        Ok(p) => p,
+        Err(e) => return ApiResponder::Err(e),
+    };
+    let video = match db.get_video(&form.video).await? {
+        Some(v) if v.user_authorized(Some(&user), &db).await? => v,
+        _ => return ApiResponder::Err(PlaylistError::VideoNotFound.into()),
+    };
+    // adding a video already in the playlist moves it
+    playlist.videos.retain(|v| *v != video.id);
+    if playlist.videos.len() >= MAX_VIDEOS {
+        return ApiResponder::Err(PlaylistError::TooManyVideos.into());
+    }
+    let position = form.position.unwrap_or(usize::MAX).min(playlist.videos.len());
+    playlist.videos.insert(position, video.id);
+    playlist.updated = Utc::now();
+    db.save_playlist(&playlist).await?;
+    playlist.into()
+}
+
+#[delete("/<id>/videos/<video>")]
+pub(crate) async fn remove_video(id: &str, video: &str, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<Playlist> {
+    let user = user?.user;
+    let mut playlist = match modifiable_playlist(id, &user, &db).await {
+        Ok(p) => p,
+        Err(e) => return ApiResponder::Err(e),
+    };
+    if !playlist.videos.iter().any(|v| v == video) {
+        return ApiResponder::Err(PlaylistError::VideoNotFound.into());
+    }
+    playlist.videos.retain(|v| v != video);
+    playlist.updated = Utc::now();
+    db.save_playlist(&playlist).await?;
+    playlist.into()
+}
+
+#[derive(Deserialize)]
+pub(crate) struct OrderForm {
+    videos: Vec<String>,
+}
+
+#[post("/<id>/order", data = "<form>", format = "json")]
+pub(crate) async fn reorder(id: &str, form: Json<OrderForm>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<Playlist> {
+    let user = user?.user;
+    let mut playlist = match modifiable_playlist(id, &user, &db).await {
+        Ok(p) => p,
+        Err(e) => return ApiResponder::Err(e),
+    };
+    let mut current = playlist.videos.clone();
+    let mut new = form.into_inner().videos;
+    let order = new.clone();
+    current.sort();
+    new.sort();
+    if current != new {
+        return ApiResponder::Err(PlaylistError::InvalidOrder.into());
+    }
+    playlist.videos = order;
+    playlist.updated = Utc::now();
+    db.save_playlist(&playlist).await?;
+    playlist.into()
+}
+
+// public page of a playlist, items are then streamed by position from `/share/playlist/<id>/<n>`
+#[get("/playlist/<id>")]
+pub(crate) async fn share(id: &str, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<PlaylistResponse> {
+    get(id, user, db).await
+}
+
+#[get("/playlist/<id>/<n>")]
+pub(crate) async fn share_item(id: &str, n: usize, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper, range: Option<Range>, ip: Option<IpAddr>) -> ShareResponder {
+    let user = user.ok().map(|u| u.user);
+    let playlist = match db.get_playlist(id).await {
+        Ok(Some(p)) => p,
+        Ok(None) => return ShareResponder::NotFound,
+        Err(_) => return ShareResponder::InternalError,
+    };
+    match playlist.user_authorized(user.as_ref(), &db).await {
+        Ok(true) => {}
+        Ok(false) => return ShareResponder::NotFound,
+        Err(_) => return ShareResponder::InternalError,
+    }
+    // positions are the ones of the share page, which only lists the videos the user can watch
+    let visible = match playlist.visible_videos(user.as_ref(), &db).await {
+        Ok(v) => v,
+        Err(_) => return ShareResponder::InternalError,
+    };
+    let video = match visible.get(n) {
+        Some(v) => &v.id,
+        None => return ShareResponder::NotFound,
+    };
+    match db.get_video_resolved(video).await {
+        Ok(Some(mut v)) => {
+            let viewer = match user {
+                Some(ref u) => Viewer::User(&u.username),
+                None => Viewer::Address(ip),
+            };
+            db.record_view(&v, viewer, ViewSource::Share).await;
+            match v.resolve_converted(&db).await {
+                Ok(()) => ShareResponder::Ok(MediaStream::from_video(range, v, CachePolicy::Restricted).await),
+                Err(_) => ShareResponder::InternalError,
+            }
+        }
+        Ok(None) => ShareResponder::NotFound,
+        Err(_) => ShareResponder::InternalError,
+    }
+}
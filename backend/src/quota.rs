@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rocket::futures::TryStreamExt;
+use rocket_db_pools::mongodb::{self, bson::{doc, oid::ObjectId, serde_helpers::chrono_datetime_as_bson_datetime, Document}, options::UpdateOptions};
+use serde::{Deserialize, Serialize};
+
+use crate::{authentication::{AuthenticationError, IsAdmin, UserGuard}, db::{error_code, DBWrapper, DUPLICATE_KEY}, game::Game, response::{ApiError, ApiResponder, ApiResponse}, user::User, video::UploadError, CONFIG};
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Usage {
+    // bytes
+    used: u64,
+    limit: Option<u64>,
+}
+
+impl Usage {
+    fn new(used: u64, limit: Option<u64>) -> Self {
+        Self { used, limit }
+    }
+
+    fn allows(&self, bytes: u64) -> bool {
+        self.limit.is_none_or(|l| self.used.saturating_add(bytes) <= l)
+    }
+}
+
+#[derive(Deserialize)]
+struct UsageEntry {
+    #[serde(rename = "_id")]
+    key: String,
+    bytes: u64,
+}
+
+// reservations older than this belong to uploads that never finished
+const RESERVATION_TIMEOUT: TimeDelta = TimeDelta::hours(1);
+// concurrent uploads make a reservation retry, up to this many times
+const RESERVE_ATTEMPTS: u32 = 10;
+
+// bytes being stored against one quota, not yet part of the usage.
+//   `seq` is bumped on every change, a reservation is only taken if nothing changed since the usage was read
+#[derive(Serialize, Deserialize, Default)]
+struct QuotaReservations {
+    #[serde(rename = "_id")]
+    scope: String,
+    seq: i64,
+    reservations: Vec<Reservation>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Reservation {
+    id: String,
+    bytes: i64,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    at: DateTime<Utc>,
+}
+
+// bytes reserved for an upload until it's stored, must be released in any case
+pub(crate) struct QuotaReservation {
+    id: String,
+    scopes: Vec<String>,
+}
+
+impl DBWrapper {
+    // bytes used by the videos matching `filter`, grouped by their `key` field.
+    //   a file shared by several videos of the same group is counted once, its converted version is included
+    async fn usage_by(&self, filter: Document, key: &str) -> Result<HashMap<String, u64>, mongodb::error::Error> {
+        self
+            .collection::<Document>(Self::VIDEOS)
+            .aggregate(vec![
+                doc! { "$match": filter },
+                doc! { "$group": { "_id": { "key": format!("${}", key), "file": "$file" } } },
+                doc! { "$lookup": {
+                    "from": Self::VIDEO_FILES,
+                    "localField": "_id.file",
+                    "foreignField": "_id",
+                    "as": "file"
+                } },
+                doc! { "$unwind": "$file" },
+                doc! { "$lookup": {
+                    "from": Self::VIDEO_FILES,
+                    "localField": "file.converted",
+                    "foreignField": "_id",
+                    "as": "converted"
+                } },
+                doc! { "$group": {
+                    "_id": "$_id.key",
+                    "bytes": { "$sum": { "$add": [{ "$ifNull": ["$file.size", 0] }, { "$sum": "$converted.size" }] } }
+                } },
+            ], None)
+            .await?
+            .map_ok(|d| mongodb::bson::from_document::<UsageEntry>(d).unwrap())
+            .map_ok(|e| (e.key, e.bytes))
+            .try_collect()
+            .await
+    }
+
+    pub(crate) async fn user_usage(&self, user: &User) -> Result<Usage, mongodb::error::Error> {
+        let used = self.usage_by(doc! { "owner": &user.username }, "owner").await?;
+        Ok(Usage::new(used.into_values().sum(), CONFIG.quotas.user))
+    }
+
+    pub(crate) async fn game_usage(&self, game: &str) -> Result<Usage, mongodb::error::Error> {
+        let used = self.usage_by(doc! { "game": game }, "game").await?;
+        Ok(Usage::new(used.into_values().sum(), CONFIG.quotas.game))
+    }
+
+    // whether `bytes` more can be stored for the user in the game
+    pub(crate) async fn check_quota(&self, user: &User, game: &str, bytes: u64) -> Result<(), ApiError> {
+        if let Some(limit) = CONFIG.quotas.user {
+            if !self.user_usage(user).await?.allows(bytes) {
+                return Err(UploadError::QuotaExceeded("user", limit).into());
+            }
+        }
+        if let Some(limit) = CONFIG.quotas.game {
+            if !self.game_usage(game).await?.allows(bytes) {
+                return Err(UploadError::QuotaExceeded("game", limit).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DBWrapper {
+    // reserves `bytes` in the quotas of the user and the game, so that concurrent uploads can't go past them together
+    pub(crate) async fn reserve_quota(&self, user: &User, game: &str, bytes: u64) -> Result<QuotaReservation, ApiError> {
+        let mut reservation = QuotaReservation { id: ObjectId::new().to_hex(), scopes: vec![] };
+        let quotas = [
+            ("user", format!("user:{}", user.username), CONFIG.quotas.user, doc! { "owner": &user.username }, "owner"),
+            ("game", format!("game:{}", game), CONFIG.quotas.game, doc! { "game": game }, "game"),
+        ];
+        for (name, scope, limit, filter, key) in quotas {
+            let Some(limit) = limit else { continue };
+            let reserved = match self.reserve_scope(&scope, &reservation.id, bytes, limit, filter, key).await {
+                Ok(r) => r,
+                Err(e) => {
+                    self.release_quota(reservation).await;
+                    return Err(e.into());
+                }
+            };
+            if !reserved {
+                self.release_quota(reservation).await;
+                return Err(UploadError::QuotaExceeded(name, limit).into());
+            }
+            reservation.scopes.push(scope);
+        }
+        Ok(reservation)
+    }
+
+    async fn reserve_scope(&self, scope: &str, id: &str, bytes: u64, limit: u64, filter: Document, key: &str) -> Result<bool, mongodb::error::Error> {
+        let reservations = self.collection::<QuotaReservations>(Self::QUOTA_RESERVATIONS);
+        for _ in 0..RESERVE_ATTEMPTS {
+            let now = Utc::now();
+            let current = reservations.find_one(doc! { "_id": scope }, None).await?.unwrap_or_default();
+            let pending = current.reservations
+                .iter()
+                .filter(|r| r.at > now - RESERVATION_TIMEOUT)
+                .map(|r| r.bytes as u64)
+                .sum::<u64>();
+            let used = self.usage_by(filter.clone(), key).await?.into_values().sum::<u64>();
+            if !Usage::new(used.saturating_add(pending), Some(limit)).allows(bytes) {
+                return Ok(false);
+            }
+            let res = reservations
+                .update_one(
+                    doc! { "_id": scope, "seq": current.seq },
+                    doc! {
+                        "$inc": { "seq": 1 },
+                        "$push": { "reservations": { "id": id, "bytes": bytes as i64, "at": now } },
+                    },
+                    UpdateOptions::builder().upsert(true).build(),
+                )
+                .await;
+            match res {
+                Ok(_) => return Ok(true),
+                // another upload reserved or released bytes in the meantime, the document exists with another `seq`
+                Err(e) if error_code(&e) == Some(DUPLICATE_KEY) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        log::warn!("gave up reserving {} bytes in quota {}, too many concurrent uploads", bytes, scope);
+        Ok(false)
+    }
+
+    // to be called once the upload is part of the usage, or failed. stale reservations are dropped along
+    pub(crate) async fn release_quota(&self, reservation: QuotaReservation) {
+        let cutoff = Utc::now() - RESERVATION_TIMEOUT;
+        for scope in reservation.scopes {
+            let res = self
+                .collection::<QuotaReservations>(Self::QUOTA_RESERVATIONS)
+                .update_one(
+                    doc! { "_id": &scope },
+                    doc! {
+                        "$inc": { "seq": 1 },
+                        "$pull": { "reservations": { "$or": [{ "id": &reservation.id }, { "at": { "$lte": cutoff } }] } },
+                    },
+                    None,
+                )
+                .await;
+            if let Err(e) = res {
+                log::error!("failed to release quota reservation {} of {}: {}", reservation.id, scope, e);
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct UserUsage {
+    username: String,
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+#[derive(Serialize)]
+pub(crate) struct GameUsage {
+    #[serde(flatten)]
+    game: Game,
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+impl GameUsage {
+    // usage of each of the games
+    pub(crate) async fn load(games: Vec<Game>, db: &DBWrapper) -> Result<Vec<Self>, mongodb::error::Error> {
+        let ids = games.iter().filter_map(|g| g.id.clone()).collect::<Vec<_>>();
+        let mut used = db.usage_by(doc! { "game": { "$in": ids } }, "game").await?;
+        Ok(games
+            .into_iter()
+            .map(|g| {
+                let bytes = g.id.as_ref().and_then(|id| used.remove(id)).unwrap_or_default();
+                Self { game: g, usage: Usage::new(bytes, CONFIG.quotas.game) }
+            })
+            .collect())
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct UsageResponse {
+    users: Vec<UserUsage>,
+    games: Vec<GameUsage>,
+}
+
+impl ApiResponse for UsageResponse {}
+
+#[get("/usage")]
+pub(crate) async fn usage(user: Result<UserGuard<IsAdmin>, AuthenticationError>, db: DBWrapper) -> ApiResponder<UsageResponse> {
+    let _ = user?;
+    let mut used = db.usage_by(doc! {}, "owner").await?;
+    let users = db
+        .get_users()
+        .await?
+        .into_iter()
+        .map(|u| {
+            let bytes = used.remove(&u.username).unwrap_or_default();
+            UserUsage { username: u.username, usage: Usage::new(bytes, CONFIG.quotas.user) }
+        })
+        .collect();
+    let games = GameUsage::load(db.get_games().await?, &db).await?;
+    UsageResponse { users, games }.into()
+}
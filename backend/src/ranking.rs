@@ -0,0 +1,212 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use rocket::futures::{StreamExt, TryStreamExt};
+use rocket_db_pools::mongodb::{self, bson::{doc, oid::ObjectId, Document}};
+use serde::{Deserialize, Serialize};
+
+use crate::{authentication::{AuthenticationError, UserGuard}, db::DBWrapper, response::{ApiErrorType, ApiResponder, ApiResponse}, user::{Permissions, User}, video::Video};
+
+// days considered by the rankings unless given
+const DEFAULT_WINDOW: u32 = 30;
+const MAX_WINDOW: u32 = 365;
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 50;
+// trending weighs each like by 1/2 every half life, older likes are ignored
+const TRENDING_HALF_LIFE: TimeDelta = TimeDelta::days(2);
+const TRENDING_WINDOW: TimeDelta = TimeDelta::days(14);
+
+// likes don't store when they were left, but their generated ids start with their creation time
+fn id_since(t: DateTime<Utc>) -> ObjectId {
+    let mut bytes = [0; 12];
+    bytes[..4].copy_from_slice(&(t.timestamp().max(0) as u32).to_be_bytes());
+    ObjectId::from_bytes(bytes)
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RankedVideo {
+    video: Video,
+    likes: i32,
+    // recency weighted likes, only for trending
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    score: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RankedUploader {
+    #[serde(rename(deserialize = "_id"))]
+    user: String,
+    // videos of the user in the game
+    videos: i32,
+    // likes received in the window
+    likes: i32,
+}
+
+impl DBWrapper {
+    // most liked videos of a game since the given time, weighted by recency when a half life is given
+    async fn get_ranked_videos(&self, game: &str, since: DateTime<Utc>, half_life: Option<TimeDelta>, limit: u32) -> Result<Vec<RankedVideo>, mongodb::error::Error> {
+        let mut pipeline = vec![doc! {"$match": {"_id": {"$gte": id_since(since)}}}];
+        let score = match half_life {
+            Some(h) => {
+                pipeline.push(doc! {"$addFields": {"_weight": {"$pow": [0.5, {"$divide": [
+                    {"$subtract": ["$$NOW", {"$toDate": "$_id"}]},
+                    h.num_milliseconds() as f64,
+                ]}]}}});
+                doc! {"$sum": "$_weight"}
+            }
+            None => doc! {"$sum": 1},
+        };
+        pipeline.extend([
+            doc! {"$group": {"_id": "$video", "likes": {"$sum": 1}, "score": score}},
+            doc! {"$lookup": {
+                "from": Self::VIDEOS,
+                "localField": "_id",
+                "foreignField": "_id",
+                "as": "video",
+            }},
+            doc! {"$unwind": "$video"},
+            doc! {"$match": {"video.game": game}},
+            doc! {"$sort": {"score": -1, "_id": 1}},
+            doc! {"$limit": limit as i64},
+        ]);
+        if half_life.is_none() {
+            pipeline.push(doc! {"$project": {"score": 0}});
+        }
+        self
+            .collection::<Document>(Self::LIKES)
+            .aggregate(pipeline, None)
+            .await?
+            .map(|d| d.map(|d| mongodb::bson::from_document::<RankedVideo>(d).unwrap()))
+            .try_collect()
+            .await
+    }
+
+    // owners of the game's videos, by likes received since the given time
+    async fn get_ranked_uploaders(&self, game: &str, since: DateTime<Utc>, limit: u32) -> Result<Vec<RankedUploader>, mongodb::error::Error> {
+        self
+            .collection::<Document>(Self::VIDEOS)
+            .aggregate(vec![
+                doc! {"$match": {"game": game}},
+                doc! {"$lookup": {
+                    "from": Self::LIKES,
+                    "let": {"video": "$_id"},
+                    "pipeline": [
+                        {"$match": {"$expr": {"$and": [
+                            {"$eq": ["$video", "$$video"]},
+                            {"$gte": ["$_id", id_since(since)]},
+                        ]}}},
+                        {"$count": "n"},
+                    ],
+                    "as": "_likes",
+                }},
+                doc! {"$group": {
+                    "_id": "$owner",
+                    "videos": {"$sum": 1},
+                    "likes": {"$sum": {"$ifNull": [{"$arrayElemAt": ["$_likes.n", 0]}, 0]}},
+                }},
+                doc! {"$sort": {"likes": -1, "videos": -1, "_id": 1}},
+                doc! {"$limit": limit as i64},
+            ], None)
+            .await?
+            .map(|d| d.map(|d| mongodb::bson::from_document::<RankedUploader>(d).unwrap()))
+            .try_collect()
+            .await
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum RankingError {
+    GameNotFound,
+    InvalidWindow,
+}
+
+impl ApiErrorType for RankingError {
+    fn ty(&self) -> &'static str {
+        match self {
+            Self::GameNotFound => "game_not_found",
+            Self::InvalidWindow => "invalid_window",
+        }
+    }
+
+    fn status(&self) -> rocket::http::Status {
+        match self {
+            Self::GameNotFound => rocket::http::Status::NotFound,
+            Self::InvalidWindow => rocket::http::Status::BadRequest,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::GameNotFound => "Game not found".to_string(),
+            Self::InvalidWindow => format!("Window must be between 1 and {} days", MAX_WINDOW),
+        }
+    }
+}
+
+// rankings are only shown to members of the game, and admins
+async fn check_game(game: &str, user: &User, db: &DBWrapper) -> Result<(), crate::response::ApiError> {
+    let member = user.allowed(Permissions::ADMIN) || db.is_user_in_game(game, &user.username).await?;
+    if member && db.get_game(game).await?.is_some() {
+        Ok(())
+    } else {
+        Err(RankingError::GameNotFound.into())
+    }
+}
+
+fn window_start(days: Option<u32>) -> Result<DateTime<Utc>, RankingError> {
+    match days.unwrap_or(DEFAULT_WINDOW) {
+        d @ 1..=MAX_WINDOW => Ok(Utc::now() - TimeDelta::days(d as i64)),
+        _ => Err(RankingError::InvalidWindow),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(transparent)]
+pub(crate) struct VideoRanking {
+    inner: Vec<RankedVideo>,
+}
+
+impl ApiResponse for VideoRanking {}
+
+#[derive(Serialize)]
+#[serde(transparent)]
+pub(crate) struct UploaderRanking {
+    inner: Vec<RankedUploader>,
+}
+
+impl ApiResponse for UploaderRanking {}
+
+// most liked videos over the last `days`
+#[get("/<game>/top?<days>&<limit>")]
+pub(crate) async fn top(game: &str, days: Option<u32>, limit: Option<u32>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<VideoRanking> {
+    let user = user?.user;
+    if let Err(e) = check_game(game, &user, &db).await {
+        return ApiResponder::Err(e);
+    }
+    let since = window_start(days)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    VideoRanking { inner: db.get_ranked_videos(game, since, None, limit).await? }.into()
+}
+
+// videos with the most recent likes
+#[get("/<game>/trending?<limit>")]
+pub(crate) async fn trending(game: &str, limit: Option<u32>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<VideoRanking> {
+    let user = user?.user;
+    if let Err(e) = check_game(game, &user, &db).await {
+        return ApiResponder::Err(e);
+    }
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let inner = db.get_ranked_videos(game, Utc::now() - TRENDING_WINDOW, Some(TRENDING_HALF_LIFE), limit).await?;
+    VideoRanking { inner }.into()
+}
+
+// users whose videos got the most likes over the last `days`
+#[get("/<game>/uploaders?<days>&<limit>")]
+pub(crate) async fn uploaders(game: &str, days: Option<u32>, limit: Option<u32>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<UploaderRanking> {
+    let user = user?.user;
+    if let Err(e) = check_game(game, &user, &db).await {
+        return ApiResponder::Err(e);
+    }
+    let since = window_start(days)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    UploaderRanking { inner: db.get_ranked_uploaders(game, since, limit).await? }.into()
+}
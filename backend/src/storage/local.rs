@@ -0,0 +1,82 @@
+use std::{io::{self, SeekFrom}, path::{Path, PathBuf}};
+
+use chrono::{DateTime, Utc};
+use rocket::{futures::stream, tokio::{fs::{self, File}, io::{AsyncReadExt, AsyncSeekExt}}};
+
+use crate::CONFIG;
+
+use super::{Chunks, LocalFile, ObjectInfo, Storage};
+
+// objects are plain files under a directory, the key being their relative path
+pub(crate) struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub(crate) fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, source: &Path) -> io::Result<()> {
+        let target = self.path(key);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        // renaming fails across filesystems
+        if fs::rename(source, &target).await.is_err() {
+            fs::copy(source, &target).await?;
+            fs::remove_file(source).await?;
+        }
+        Ok(())
+    }
+
+    async fn get_range(&self, key: &str, first: u64, last: u64) -> io::Result<Chunks> {
+        let mut file = File::open(self.path(key)).await?;
+        file.seek(SeekFrom::Start(first)).await?;
+        let chunks = stream::unfold((file, last - first + 1), |(mut file, left)| async move {
+            if left == 0 {
+                return None;
+            }
+            let len = left.min(CONFIG.media_chunk);
+            let mut buf = vec![0; len as usize];
+            match file.read_exact(&mut buf).await {
+                Ok(_) => Some((Ok(buf), (file, left - len))),
+                // stop after the error
+                Err(e) => Some((Err(e), (file, 0))),
+            }
+        });
+        Ok(Box::pin(chunks))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<Option<ObjectInfo>> {
+        match fs::metadata(self.path(key)).await {
+            Ok(m) => Ok(Some(ObjectInfo {
+                size: m.len(),
+                modified: m.modified().ok().map(DateTime::<Utc>::from),
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn local_file(&self, key: &str) -> io::Result<LocalFile> {
+        Ok(LocalFile {
+            path: self.path(key),
+            temporary: false,
+        })
+    }
+}
@@ -0,0 +1,100 @@
+use std::{io, path::{Path, PathBuf}, pin::Pin};
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use rocket::futures::{Stream, TryStreamExt};
+use rocket_db_pools::mongodb::bson::oid::ObjectId;
+
+use crate::config::{StorageConfig, CONFIG};
+
+pub(crate) mod local;
+pub(crate) mod s3;
+
+lazy_static! {
+    // where video files and thumbnails are kept, selected by the `storage` section of the config
+    pub(crate) static ref STORAGE: Box<dyn Storage> = match CONFIG.storage {
+        StorageConfig::Local => Box::new(local::LocalStorage::new(&CONFIG.video_storage)),
+        StorageConfig::S3(ref c) => Box::new(s3::S3Storage::new(c)),
+    };
+}
+
+// content of an object, in chunks
+pub(crate) type Chunks = Pin<Box<dyn Stream<Item = io::Result<Vec<u8>>> + Send>>;
+
+pub(crate) struct ObjectInfo {
+    pub(crate) size: u64,
+    pub(crate) modified: Option<DateTime<Utc>>,
+}
+
+// a file that external tools can read. downloads of remote objects are removed on drop
+pub(crate) struct LocalFile {
+    path: PathBuf,
+    temporary: bool,
+}
+
+impl LocalFile {
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for LocalFile {
+    fn drop(&mut self) {
+        if self.temporary {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                log::warn!("failed to remove download {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+// keys are relative paths, like `<file id>` or `thumbs/<file id>.jpg`.
+//   working files (uploads in progress, hls, sprites, subtitles) always stay in `video_storage`
+#[async_trait]
+pub(crate) trait Storage: Send + Sync {
+    // moves a local file into the storage, replacing the object if it exists
+    async fn put(&self, key: &str, source: &Path) -> io::Result<()>;
+
+    // bytes from `first` to `last`, inclusive
+    async fn get_range(&self, key: &str, first: u64, last: u64) -> io::Result<Chunks>;
+
+    // removing a missing object isn't an error
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    // none if the object doesn't exist
+    async fn stat(&self, key: &str) -> io::Result<Option<ObjectInfo>>;
+
+    async fn local_file(&self, key: &str) -> io::Result<LocalFile>;
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        Ok(self.stat(key).await?.is_some())
+    }
+
+    async fn size(&self, key: &str) -> io::Result<Option<u64>> {
+        Ok(self.stat(key).await?.map(|i| i.size))
+    }
+
+    // whole content, meant for small objects like thumbnails
+    async fn get(&self, key: &str) -> io::Result<Option<(Vec<u8>, ObjectInfo)>> {
+        let info = match self.stat(key).await? {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        if info.size == 0 {
+            return Ok(Some((vec![], info)));
+        }
+        let content = self
+            .get_range(key, 0, info.size - 1)
+            .await?
+            .try_concat()
+            .await?;
+        Ok(Some((content, info)))
+    }
+}
+
+// where remote objects are downloaded for external tools
+fn download_path(key: &str) -> PathBuf {
+    Path::new(&CONFIG.video_storage)
+        .join("downloads")
+        .join(format!("{}_{}", ObjectId::new().to_hex(), key.replace('/', "_")))
+}
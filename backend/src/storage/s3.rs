@@ -0,0 +1,193 @@
+use std::{io, path::Path};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::{header::{CONTENT_LENGTH, LAST_MODIFIED, RANGE}, Body, Client, Method, RequestBuilder, Response, StatusCode, Url};
+use rocket::{futures::{stream, StreamExt, TryStreamExt}, tokio::{fs::{self, File}, io::AsyncWriteExt}};
+use sha2::{Digest, Sha256};
+use tokio_util::io::ReaderStream;
+
+use crate::config::S3Config;
+
+use super::{download_path, Chunks, LocalFile, ObjectInfo, Storage};
+
+// bodies aren't hashed, the connection is trusted to carry them unaltered
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+// objects are kept in a bucket of an S3 compatible service
+pub(crate) struct S3Storage {
+    client: Client,
+    // root of the bucket, ending with a slash
+    base: Url,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    prefix: String,
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// percent-encodes everything but unreserved characters and slashes, as the signature requires
+fn encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl S3Storage {
+    pub(crate) fn new(config: &S3Config) -> Self {
+        let mut base = Url::parse(&config.endpoint).expect("Invalid storage endpoint");
+        let root = base.path().trim_end_matches('/').to_string();
+        if config.path_style {
+            base.set_path(&format!("{}/{}/", root, encode_path(&config.bucket)));
+        } else {
+            let host = format!("{}.{}", config.bucket, base.host_str().expect("Storage endpoint has no host"));
+            base.set_host(Some(&host)).expect("Invalid storage bucket");
+            base.set_path(&format!("{}/", root));
+        }
+        Self {
+            client: Client::new(),
+            base,
+            region: config.region.clone(),
+            access_key: config.access_key.clone(),
+            secret_key: config.secret_key.clone(),
+            prefix: config.prefix.clone(),
+        }
+    }
+
+    fn url(&self, key: &str) -> Url {
+        let mut url = self.base.clone();
+        url.set_path(&format!("{}{}", self.base.path(), encode_path(&format!("{}{}", self.prefix, key))));
+        url
+    }
+
+    // request signed with AWS signature version 4,
+    //   see https://docs.aws.amazon.com/AmazonS3/latest/API/sig-v4-header-based-auth.html
+    fn request(&self, method: Method, key: &str) -> RequestBuilder {
+        let url = self.url(key);
+        let datetime = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let date = &datetime[..8];
+        let host = match url.port() {
+            Some(p) => format!("{}:{}", url.host_str().unwrap_or_default(), p),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let canonical = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, url.path(), host, UNSIGNED_PAYLOAD, datetime, SIGNED_HEADERS, UNSIGNED_PAYLOAD,
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", datetime, scope, hex::encode(Sha256::digest(canonical)));
+        let signing_key = [date, &self.region, "s3", "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{}", self.secret_key).into_bytes(), |k, part| hmac(&k, part));
+        let signature = hex::encode(hmac(&signing_key, &to_sign));
+        self.client
+            .request(method, url)
+            .header("x-amz-date", datetime)
+            .header("x-amz-content-sha256", UNSIGNED_PAYLOAD)
+            .header("Authorization", format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key, scope, SIGNED_HEADERS, signature,
+            ))
+    }
+
+    async fn send(&self, request: RequestBuilder) -> io::Result<Response> {
+        request.send().await.map_err(io::Error::other)
+    }
+
+    fn failure(res: &Response, key: &str) -> io::Error {
+        io::Error::other(format!("storage responded {} for object {}", res.status(), key))
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, source: &Path) -> io::Result<()> {
+        let file = File::open(source).await?;
+        let len = file.metadata().await?.len();
+        // a single upload is limited to 5GB by aws, minio doesn't have such limit
+        let res = self.send(self
+            .request(Method::PUT, key)
+            .header(CONTENT_LENGTH, len)
+            .body(Body::wrap_stream(ReaderStream::new(file)))
+        ).await?;
+        if !res.status().is_success() {
+            return Err(Self::failure(&res, key));
+        }
+        fs::remove_file(source).await
+    }
+
+    async fn get_range(&self, key: &str, first: u64, last: u64) -> io::Result<Chunks> {
+        let res = self.send(self
+            .request(Method::GET, key)
+            .header(RANGE, format!("bytes={}-{}", first, last))
+        ).await?;
+        match res.status() {
+            StatusCode::PARTIAL_CONTENT => {}
+            // the range was ignored, the body is only usable if it covers the whole object
+            StatusCode::OK if first == 0 && res.content_length() == Some(last + 1) => {}
+            StatusCode::NOT_FOUND => return Err(io::Error::new(io::ErrorKind::NotFound, format!("object {} not found", key))),
+            _ => return Err(Self::failure(&res, key)),
+        }
+        let chunks = res
+            .bytes_stream()
+            .map_ok(|b| b.to_vec())
+            .map_err(io::Error::other);
+        Ok(Box::pin(chunks))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        let res = self.send(self.request(Method::DELETE, key)).await?;
+        match res.status() {
+            s if s.is_success() || s == StatusCode::NOT_FOUND => Ok(()),
+            _ => Err(Self::failure(&res, key)),
+        }
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<Option<ObjectInfo>> {
+        let res = self.send(self.request(Method::HEAD, key)).await?;
+        match res.status() {
+            StatusCode::NOT_FOUND => return Ok(None),
+            s if !s.is_success() => return Err(Self::failure(&res, key)),
+            _ => {}
+        }
+        let headers = res.headers();
+        let size = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| io::Error::other(format!("storage didn't send the size of object {}", key)))?;
+        let modified = headers
+            .get(LAST_MODIFIED)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+            .map(|d| d.with_timezone(&Utc));
+        Ok(Some(ObjectInfo { size, modified }))
+    }
+
+    // downloads the object, external tools can't read from the bucket
+    async fn local_file(&self, key: &str) -> io::Result<LocalFile> {
+        let mut chunks: Chunks = match self.size(key).await? {
+            Some(0) => Box::pin(stream::empty()),
+            Some(size) => self.get_range(key, 0, size - 1).await?,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("object {} not found", key))),
+        };
+        let path = download_path(key);
+        let mut file = File::create(&path).await?;
+        // removes the partial download on failure
+        let local = LocalFile { path, temporary: true };
+        while let Some(chunk) = chunks.next().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.flush().await?;
+        Ok(local)
+    }
+}
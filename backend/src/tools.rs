@@ -0,0 +1,141 @@
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::process::{ExitStatus, Stdio};
+
+use chrono::TimeDelta;
+use lazy_static::lazy_static;
+use rocket::tokio::{process::Command, sync::Semaphore, time::timeout};
+
+use crate::config::CONFIG;
+
+lazy_static! {
+    // shared by every external process, so that uploads and background jobs can't overload the host
+    static ref SLOTS: Semaphore = Semaphore::new(CONFIG.tools.max_concurrent);
+}
+
+// keep error messages readable, ffmpeg can be very verbose
+const STDERR_TAIL: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Tool {
+    Ffmpeg,
+    Ffprobe,
+}
+
+impl Tool {
+    fn binary(&self) -> &'static str {
+        match self {
+            Self::Ffmpeg => &CONFIG.tools.ffmpeg,
+            Self::Ffprobe => &CONFIG.tools.ffprobe,
+        }
+    }
+
+    fn default_timeout(&self) -> TimeDelta {
+        match self {
+            Self::Ffmpeg => CONFIG.tools.process_timeout,
+            Self::Ffprobe => CONFIG.tools.probe_timeout,
+        }
+    }
+
+    pub(crate) fn command(self) -> ToolCommand {
+        let mut inner = Command::new(self.binary());
+        inner
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        ToolCommand {
+            tool: self,
+            inner,
+            timeout: self.default_timeout(),
+        }
+    }
+}
+
+impl Display for Tool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ffmpeg => write!(f, "ffmpeg"),
+            Self::Ffprobe => write!(f, "ffprobe"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum ToolError {
+    Spawn(Tool, std::io::Error),
+    Timeout(Tool, TimeDelta),
+    Failed {
+        tool: Tool,
+        status: ExitStatus,
+        stderr: String,
+    },
+}
+
+impl Display for ToolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Spawn(t, e) => write!(f, "failed to run {}: {}", t, e),
+            Self::Timeout(t, d) => write!(f, "{} timed out after {}s", t, d.num_seconds()),
+            Self::Failed { tool, status, stderr } => write!(f, "{} exited with {}: {}", tool, status, stderr),
+        }
+    }
+}
+
+pub(crate) struct ToolCommand {
+    tool: Tool,
+    inner: Command,
+    timeout: TimeDelta,
+}
+
+impl ToolCommand {
+    pub(crate) fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.inner.arg(arg);
+        self
+    }
+
+    pub(crate) fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.inner.args(args);
+        self
+    }
+
+    // placed before an input uploaded by a user. it can only be read as a local file with one of the given demuxers,
+    //   so that playlists and concat lists can't make the tool open other files or urls
+    pub(crate) fn untrusted_input(&mut self, formats: &str) -> &mut Self {
+        self.args(["-protocol_whitelist", "file", "-format_whitelist", formats])
+    }
+
+    pub(crate) fn timeout(&mut self, timeout: TimeDelta) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // waits for a free slot, then runs the process to completion returning its stdout
+    pub(crate) async fn run(&mut self) -> Result<Vec<u8>, ToolError> {
+        let _slot = SLOTS.acquire().await.expect("tool semaphore is never closed");
+        let child = self.inner
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| ToolError::Spawn(self.tool, e))?;
+        // the child is killed when the future gets dropped by the timeout
+        let output = match timeout(self.timeout.to_std().unwrap_or_default(), child.wait_with_output()).await {
+            Ok(o) => o.map_err(|e| ToolError::Spawn(self.tool, e))?,
+            Err(_) => return Err(ToolError::Timeout(self.tool, self.timeout)),
+        };
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let start = stderr.len().saturating_sub(STDERR_TAIL);
+            let start = (start..stderr.len()).find(|i| stderr.is_char_boundary(*i)).unwrap_or(0);
+            Err(ToolError::Failed {
+                tool: self.tool,
+                status: output.status,
+                stderr: stderr[start..].trim().to_string(),
+            })
+        }
+    }
+}
@@ -7,9 +7,9 @@ use serde::{Deserialize, Serialize};
 use rand::{rngs::OsRng, RngCore};
 use base64::{Engine, prelude::BASE64_URL_SAFE};
 use argon2::{Argon2, PasswordHasher, PasswordVerifier, PasswordHash, password_hash::SaltString};
-use crate::{authentication::{AuthenticationError, IsAdmin, OkExpired, UserGuard}, config::CONFIG, db::DBWrapper, response::{ApiErrorType, ApiResponder, ApiResponse}};
+use crate::{authentication::{AuthenticationError, IsAdmin, OkExpired, UserGuard}, config::CONFIG, db::DBWrapper, quota::{GameUsage, Usage}, response::{ApiErrorType, ApiResponder, ApiResponse}};
 
-fn secure_rnd_string() -> String {
+pub(crate) fn secure_rnd_string() -> String {
     let mut rng = OsRng;
     let mut bytes = [0u8; 32];
     rng.fill_bytes(&mut bytes);
@@ -107,7 +107,7 @@ impl Permissions {
 }
 
 impl User {
-    fn password_hash(password: String) -> String {
+    pub(crate) fn password_hash(password: String) -> String {
         let salt = SaltString::generate(&mut OsRng);
         let argon2 = Argon2::default();
         argon2.hash_password(password.as_bytes(), &salt).unwrap().to_string()
@@ -151,8 +151,12 @@ impl User {
     }
 
     pub(crate) fn verify_password(&self, password: String) -> bool {
+        Self::verify_password_hash(&self.password_hash, &password)
+    }
+
+    pub(crate) fn verify_password_hash(hash: &str, password: &str) -> bool {
         let argon2 = Argon2::default();
-        let hash = PasswordHash::new(&self.password_hash).unwrap();
+        let hash = PasswordHash::new(hash).unwrap();
         argon2.verify_password(password.as_bytes(), &hash).is_ok()
     }
 
@@ -297,18 +301,23 @@ pub(crate) struct MeResponse {
     username: String,
     is_admin: bool,
     password_reset: bool,
+    // storage used by the user's videos, and by the videos of each of the user's games
+    usage: Usage,
+    games_usage: Vec<GameUsage>,
 }
 
 impl ApiResponse for MeResponse {}
 
 #[get("/me")]
-pub(crate) async fn me(user: Result<UserGuard<OkExpired>, AuthenticationError>) -> ApiResponder<MeResponse> {
+pub(crate) async fn me(user: Result<UserGuard<OkExpired>, AuthenticationError>, db: DBWrapper) -> ApiResponder<MeResponse> {
     let user = user?.user;
 
     let is_admin = user.allowed(Permissions::ADMIN);
+    let usage = db.user_usage(&user).await?;
+    let games_usage = GameUsage::load(db.get_user_games(&user).await?, &db).await?;
     let username = user.username;
     let password_reset = user.password_reset;
-    MeResponse { username, is_admin, password_reset }.into()
+    MeResponse { username, is_admin, password_reset, usage, games_usage }.into()
 }
 
 
@@ -383,10 +392,7 @@ pub(crate) async fn patch(form: Json<PatchForm>, username: &str, user: Result<Us
     let user = user?.user;
     // does target user exist?
     let target_user = db.get_user(username).await?;
-    if target_user.is_none() {
-        ApiResponder::Err(PostError::UserNotFound.into())
-    } else {
-        let mut target_user = target_user.unwrap();
+    if let Some(mut target_user) = target_user {
         // modify permissions only if logged user is admin
         if let Some(permissions) = permissions {
             if !user.allowed(Permissions::ADMIN) {
@@ -407,6 +413,8 @@ pub(crate) async fn patch(form: Json<PatchForm>, username: &str, user: Result<Us
         } else {
             ApiResponder::Err(AuthenticationError::InsufficientPermissions(Permissions::ADMIN).into())
         }
+    } else {
+        ApiResponder::Err(PostError::UserNotFound.into())
     }
 }
 
@@ -0,0 +1,348 @@
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+
+use chrono::{NaiveDate, NaiveTime, TimeDelta, Utc};
+use lazy_static::lazy_static;
+use rocket::futures::{StreamExt, TryStreamExt};
+use rocket_db_pools::mongodb::{self, bson::{doc, Bson, Document}, options::UpdateOptions};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{authentication::{AuthenticationError, UserGuard}, db::{error_code, DBWrapper, DUPLICATE_KEY}, response::{ApiErrorType, ApiResponder, ApiResponse}, user::{Permissions, User}, CONFIG};
+
+use super::Video;
+
+lazy_static! {
+    static ref VIEWER_SALT: String = CONFIG.analytics.viewer_salt
+        .clone()
+        .unwrap_or_else(|| hex::encode(rand::random::<[u8; 16]>()));
+}
+
+// repeated views of the same video by the same viewer within this window count once
+pub(crate) const VIEW_WINDOW: TimeDelta = TimeDelta::minutes(30);
+// default span of the analytics endpoints, in days
+const DEFAULT_SPAN: i64 = 30;
+const MAX_SPAN: i64 = 366;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ViewSource {
+    // through a media token, from the app
+    InApp,
+    // through a public share link
+    Share,
+}
+
+// who watched, users are identified by name and anonymous viewers by address.
+//   only a salted hash of either is stored
+pub(crate) enum Viewer<'a> {
+    User(&'a str),
+    Address(Option<IpAddr>),
+}
+
+impl Viewer<'_> {
+    pub(crate) fn key(&self) -> String {
+        let id = match self {
+            Self::User(u) => format!("user:{}", u),
+            Self::Address(Some(ip)) => format!("ip:{}", ip),
+            Self::Address(None) => return "unknown".to_string(),
+        };
+        hex::encode(Sha256::digest(format!("{}:{}", *VIEWER_SALT, id)))
+    }
+}
+
+// last counted view of a video per viewer, expired through a ttl index once outside the window
+#[derive(Serialize, Deserialize, Debug)]
+struct RecentView {
+    video: String,
+    viewer: String,
+    #[serde(with = "mongodb::bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    at: chrono::DateTime<Utc>,
+}
+
+// views of a video on a single day
+#[derive(Serialize, Deserialize, Debug)]
+struct DailyViews {
+    video: String,
+    // game of the video when it was viewed, videos moved to another game keep their past views in the old one
+    game: String,
+    // yyyy-mm-dd, utc
+    day: String,
+    #[serde(default)]
+    in_app: u64,
+    #[serde(default)]
+    share: u64,
+    // hashes of the viewers
+    #[serde(default)]
+    viewers: Vec<String>,
+}
+
+impl DBWrapper {
+    // starts a view in a collection of recent views unless the viewer has one inside the window, returns its id.
+    //   `key` identifies the viewer and what they watch, it must be backed by a unique index
+    pub(crate) async fn start_recent_view(&self, collection: &'static str, key: Document) -> Result<Option<Bson>, mongodb::error::Error> {
+        let now = Utc::now();
+        let views = self.collection::<Document>(collection);
+        let mut fresh = key.clone();
+        fresh.insert("at", doc! {"$gt": now - VIEW_WINDOW});
+        match views.update_one(fresh, doc! {"$setOnInsert": {"at": now}}, UpdateOptions::builder().upsert(true).build()).await {
+            Ok(res) => Ok(res.upserted_id),
+            // either a concurrent request started the view, or the last one is past the window and wasn't expired yet
+            Err(e) if error_code(&e) == Some(DUPLICATE_KEY) => {
+                let mut stale = key;
+                stale.insert("at", doc! {"$lte": now - VIEW_WINDOW});
+                Ok(views
+                    .find_one_and_update(stale, doc! {"$set": {"at": now}}, None)
+                    .await?
+                    .and_then(|v| v.get("_id").cloned()))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // counts a view unless the viewer already watched the video recently, returns whether it was counted.
+    //   errors are only logged since they must not interrupt playback
+    pub(crate) async fn record_view(&self, video: &Video, viewer: Viewer<'_>, source: ViewSource) -> bool {
+        match self.try_record_view(video, viewer, source).await {
+            Ok(counted) => counted,
+            Err(e) => {
+                warn!("failed to record view of {}: {}", video.id, e);
+                false
+            }
+        }
+    }
+
+    async fn try_record_view(&self, video: &Video, viewer: Viewer<'_>, source: ViewSource) -> Result<bool, mongodb::error::Error> {
+        let viewer = viewer.key();
+        if self.start_recent_view(Self::RECENT_VIEWS, doc! {"video": &video.id, "viewer": &viewer}).await?.is_none() {
+            return Ok(false);
+        }
+        let day = Utc::now().date_naive();
+        let counter = match source {
+            ViewSource::InApp => "in_app",
+            ViewSource::Share => "share",
+        };
+        self
+            .collection::<DailyViews>(Self::VIEW_STATS)
+            .update_one(
+                doc! {"video": &video.id, "day": day.to_string()},
+                doc! {
+                    "$inc": {counter: 1},
+                    "$addToSet": {"viewers": &viewer},
+                    // start of the day, expired through a ttl index after the retention
+                    "$setOnInsert": {"game": &video.game, "date": mongodb::bson::DateTime::from_chrono(day.and_time(NaiveTime::MIN).and_utc())},
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await
+            .map(|_| true)
+    }
+
+    async fn get_daily_views(&self, filter: mongodb::bson::Document, from: NaiveDate, to: NaiveDate) -> Result<Vec<DailyViews>, mongodb::error::Error> {
+        let mut filter = filter;
+        filter.insert("day", doc! {"$gte": from.to_string(), "$lte": to.to_string()});
+        self
+            .collection::<DailyViews>(Self::VIEW_STATS)
+            .find(filter, mongodb::options::FindOptions::builder().sort(doc! {"day": 1}).build())
+            .await?
+            .try_collect()
+            .await
+    }
+
+    async fn get_owned_video_ids(&self, user: &User) -> Result<Vec<String>, mongodb::error::Error> {
+        self
+            .collection::<Document>(Self::VIDEOS)
+            .find(doc! {"owner": &user.username}, mongodb::options::FindOptions::builder().projection(doc! {"_id": 1}).build())
+            .await?
+            .map(|d| d.map(|d| d.get_str("_id").unwrap().to_string()))
+            .try_collect()
+            .await
+    }
+
+    pub(crate) async fn delete_video_views(&self, video: &str) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<RecentView>(Self::RECENT_VIEWS)
+            .delete_many(doc! {"video": video}, None)
+            .await?;
+        self
+            .collection::<DailyViews>(Self::VIEW_STATS)
+            .delete_many(doc! {"video": video}, None)
+            .await
+            .map(|_| ())
+    }
+}
+
+#[derive(Serialize, Default)]
+pub(crate) struct ViewCounts {
+    views: u64,
+    in_app: u64,
+    share: u64,
+    unique_viewers: usize,
+}
+
+impl ViewCounts {
+    fn from_days<'a>(days: impl IntoIterator<Item = &'a DailyViews>) -> Self {
+        let mut counts = Self::default();
+        let mut viewers = HashSet::new();
+        for d in days {
+            counts.in_app += d.in_app;
+            counts.share += d.share;
+            viewers.extend(d.viewers.iter());
+        }
+        counts.views = counts.in_app + counts.share;
+        counts.unique_viewers = viewers.len();
+        counts
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct DayCounts {
+    day: String,
+    #[serde(flatten)]
+    counts: ViewCounts,
+}
+
+#[derive(Serialize)]
+pub(crate) struct VideoCounts {
+    video: String,
+    #[serde(flatten)]
+    counts: ViewCounts,
+}
+
+#[derive(Serialize)]
+pub(crate) struct AnalyticsResponse {
+    from: String,
+    to: String,
+    #[serde(flatten)]
+    total: ViewCounts,
+    // only days with views are listed
+    days: Vec<DayCounts>,
+    // per-video breakdown, most viewed first. only for games
+    #[serde(skip_serializing_if = "Option::is_none")]
+    videos: Option<Vec<VideoCounts>>,
+}
+
+impl ApiResponse for AnalyticsResponse {}
+
+fn group_by<'a>(days: &'a [DailyViews], key: impl Fn(&'a DailyViews) -> &'a str) -> BTreeMap<&'a str, Vec<&'a DailyViews>> {
+    let mut groups: BTreeMap<&str, Vec<&DailyViews>> = BTreeMap::new();
+    for d in days {
+        groups.entry(key(d)).or_default().push(d);
+    }
+    groups
+}
+
+impl AnalyticsResponse {
+    fn new(from: NaiveDate, to: NaiveDate, days: &[DailyViews], per_video: bool) -> Self {
+        let videos = per_video.then(|| {
+            let mut videos = group_by(days, |d| &d.video)
+                .into_iter()
+                .map(|(video, days)| VideoCounts { video: video.to_string(), counts: ViewCounts::from_days(days) })
+                .collect::<Vec<_>>();
+            videos.sort_by(|a, b| b.counts.views.cmp(&a.counts.views).then_with(|| a.video.cmp(&b.video)));
+            videos
+        });
+        Self {
+            from: from.to_string(),
+            to: to.to_string(),
+            total: ViewCounts::from_days(days),
+            days: group_by(days, |d| &d.day)
+                .into_iter()
+                .map(|(day, days)| DayCounts { day: day.to_string(), counts: ViewCounts::from_days(days) })
+                .collect(),
+            videos,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum AnalyticsError {
+    VideoNotFound,
+    GameNotFound,
+    InvalidDate(String),
+    InvalidRange,
+}
+
+impl ApiErrorType for AnalyticsError {
+    fn ty(&self) -> &'static str {
+        match self {
+            Self::VideoNotFound => "video_not_found",
+            Self::GameNotFound => "game_not_found",
+            Self::InvalidDate(_) => "invalid_date",
+            Self::InvalidRange => "invalid_range",
+        }
+    }
+
+    fn status(&self) -> rocket::http::Status {
+        match self {
+            Self::VideoNotFound => rocket::http::Status::NotFound,
+            Self::GameNotFound => rocket::http::Status::NotFound,
+            Self::InvalidDate(_) => rocket::http::Status::BadRequest,
+            Self::InvalidRange => rocket::http::Status::BadRequest,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::VideoNotFound => "Video not found".to_string(),
+            Self::GameNotFound => "Game not found".to_string(),
+            Self::InvalidDate(d) => format!("Invalid date: {}", d),
+            Self::InvalidRange => format!("Range must end after it starts and span at most {} days", MAX_SPAN),
+        }
+    }
+}
+
+// inclusive range of days, the last `DEFAULT_SPAN` days by default
+fn date_range(from: Option<&str>, to: Option<&str>) -> Result<(NaiveDate, NaiveDate), AnalyticsError> {
+    let parse = |d: &str| NaiveDate::parse_from_str(d, "%Y-%m-%d").map_err(|_| AnalyticsError::InvalidDate(d.to_string()));
+    let to = match to {
+        Some(d) => parse(d)?,
+        None => Utc::now().date_naive(),
+    };
+    let from = match from {
+        Some(d) => parse(d)?,
+        None => to - TimeDelta::days(DEFAULT_SPAN - 1),
+    };
+    if from > to || (to - from).num_days() >= MAX_SPAN {
+        return Err(AnalyticsError::InvalidRange);
+    }
+    Ok((from, to))
+}
+
+fn can_view_analytics(video: &Video, user: &User) -> bool {
+    video.is_owner(user) || user.allowed(Permissions::ADMIN)
+}
+
+// views of a single video, for its owner and admins
+#[get("/<video>/analytics?<from>&<to>")]
+pub(crate) async fn video(video: &str, from: Option<&str>, to: Option<&str>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<AnalyticsResponse> {
+    let user = user?.user;
+    let video = match db.get_video(video).await? {
+        Some(v) if can_view_analytics(&v, &user) => v,
+        Some(v) if v.user_authorized(Some(&user), &db).await? => return AuthenticationError::InsufficientPermissions(Permissions::ADMIN).into(),
+        _ => return ApiResponder::Err(AnalyticsError::VideoNotFound.into()),
+    };
+    let (from, to) = date_range(from, to)?;
+    let days = db.get_daily_views(doc! {"video": &video.id}, from, to).await?;
+    AnalyticsResponse::new(from, to, &days, false).into()
+}
+
+// views of the videos of a game. admins see all of them, the other members only the videos they own
+#[get("/<game>/analytics?<from>&<to>")]
+pub(crate) async fn game(game: &str, from: Option<&str>, to: Option<&str>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<AnalyticsResponse> {
+    let user = user?.user;
+    if db.get_game(game).await?.is_none() {
+        return ApiResponder::Err(AnalyticsError::GameNotFound.into());
+    }
+    let filter = if user.allowed(Permissions::ADMIN) {
+        doc! {"game": game}
+    } else if db.is_user_in_game(game, &user.username).await? {
+        doc! {"game": game, "video": {"$in": db.get_owned_video_ids(&user).await?}}
+    } else {
+        return AuthenticationError::InsufficientPermissions(Permissions::ADMIN).into();
+    };
+    let (from, to) = date_range(from, to)?;
+    let days = db.get_daily_views(filter, from, to).await?;
+    AnalyticsResponse::new(from, to, &days, true).into()
+}
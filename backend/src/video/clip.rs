@@ -0,0 +1,188 @@
+use std::path::{Path, PathBuf};
+
+use rocket::serde::json::Json;
+use rocket_db_pools::mongodb::{self, bson::{doc, oid::ObjectId}};
+use serde::{Deserialize, Serialize};
+
+use crate::{authentication::{AuthenticationError, UserGuard}, config::CONFIG, db::DBWrapper, response::{ApiErrorType, ApiResponder}, tools::{Tool, ToolError}, user::Permissions};
+
+use super::file::{Format, VideoFile};
+use super::{ingest, IngestSource, UploadError, Video};
+
+// a cut can start on a keyframe this far from the requested start and still be stream copied
+const KEYFRAME_TOLERANCE: f64 = 0.05;
+
+// where a clip was cut from
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ClipSource {
+    pub(crate) video: String,
+    pub(crate) start: f64,
+    pub(crate) end: f64,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum ClipError {
+    VideoNotFound,
+    InvalidRange,
+    CutError,
+}
+
+impl ApiErrorType for ClipError {
+    fn ty(&self) -> &'static str {
+        match self {
+            Self::VideoNotFound => "video_not_found",
+            Self::InvalidRange => "invalid_range",
+            Self::CutError => "cut_error",
+        }
+    }
+
+    fn status(&self) -> rocket::http::Status {
+        match self {
+            Self::VideoNotFound => rocket::http::Status::NotFound,
+            Self::InvalidRange => rocket::http::Status::BadRequest,
+            Self::CutError => rocket::http::Status::InternalServerError,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::VideoNotFound => "Video not found".to_string(),
+            Self::InvalidRange => "Clip range must be non empty and inside the video".to_string(),
+            Self::CutError => "Error while cutting clip".to_string(),
+        }
+    }
+}
+
+// whether the first video stream has a keyframe close enough to `at`
+async fn keyframe_at(source: &Path, at: f64) -> Result<bool, String> {
+    let out = Tool::Ffprobe.command()
+        .args(["-v", "error", "-select_streams", "v:0", "-skip_frame", "nokey"])
+        .args(["-show_entries", "frame=best_effort_timestamp_time", "-of", "csv=p=0"])
+        .arg("-read_intervals")
+        .arg(format!("{}%{}", (at - 10.).max(0.), at + 1.))
+        .arg(source)
+        .run()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(String::from_utf8_lossy(&out)
+        .lines()
+        .filter_map(|l| l.trim().trim_end_matches(',').parse::<f64>().ok())
+        .any(|t| (t - at).abs() <= KEYFRAME_TOLERANCE))
+}
+
+fn clip_path(ext: &str) -> PathBuf {
+    Path::new(&CONFIG.video_storage).join("transcode").join(format!("clip_{}.{}", ObjectId::new().to_hex(), ext))
+}
+
+// only the first video and audio streams are kept, like transcoded files.
+//   subtitle, data and attachment streams often can't be muxed in the target container
+async fn run_cut(source: &Path, start: f64, end: f64, copy: bool, target: &Path) -> Result<(), ToolError> {
+    let mut cmd = Tool::Ffmpeg.command();
+    cmd
+        .args(["-y", "-v", "error", "-ss", &start.to_string(), "-i"])
+        .arg(source)
+        .args(["-t", &(end - start).to_string()])
+        .args(["-map", "0:v:0?", "-map", "0:a:0?"]);
+    if copy {
+        cmd.args(["-c", "copy", "-avoid_negative_ts", "make_zero"]);
+    } else {
+        cmd
+            .args(["-c:v", "libx264", "-preset", "veryfast", "-crf", "20", "-pix_fmt", "yuv420p"])
+            .args(["-c:a", "aac", "-b:a", "160k"]);
+    }
+    if target.extension().is_some_and(|e| e == "mp4") {
+        cmd.args(["-movflags", "+faststart"]);
+    }
+    let res = cmd.arg(target).run().await.map(|_| ());
+    if res.is_err() {
+        let _ = std::fs::remove_file(target);
+    }
+    res
+}
+
+async fn cut(file: &VideoFile, start: f64, end: f64) -> Result<PathBuf, String> {
+    let source = file.local_file().await.map_err(|e| e.to_string())?;
+    let source = source.path();
+    if !file.has_video() || keyframe_at(source, start).await? {
+        let target = clip_path(if matches!(file.format, Format::Mkv) { "mkv" } else { "mp4" });
+        match run_cut(source, start, end, true, &target).await {
+            Ok(()) => return Ok(target),
+            // the streams can't be copied in the container, re-encoding them always works
+            Err(e) => log::warn!("stream copying a clip of file {} failed, re-encoding: {}", file.id, e),
+        }
+    }
+    // otherwise the start falls between keyframes, only a re-encode can cut there
+    let target = clip_path("mp4");
+    run_cut(source, start, end, false, &target).await.map_err(|e| e.to_string())?;
+    Ok(target)
+}
+
+impl DBWrapper {
+    async fn set_clip_source(&self, video: &str, clip: &ClipSource) -> Result<(), mongodb::error::Error> {
+        let clip = mongodb::bson::to_bson(clip)?;
+        self
+            .collection::<Video>(Self::VIDEOS)
+            .update_one(doc! { "_id": video }, doc! { "$set": { "clip": clip } }, None)
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ClipForm {
+    // seconds from the start of the source video
+    start: f64,
+    end: f64,
+    name: Option<String>,
+    // defaults to the source video's visibility
+    public: Option<bool>,
+}
+
+#[post("/<video>/clip", data = "<form>", format = "json")]
+pub(crate) async fn create(video: &str, form: Json<ClipForm>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<Video> {
+    let user = user?.user;
+    if !user.allowed(Permissions::ADD_VIDEOS) {
+        return AuthenticationError::InsufficientPermissions(Permissions::ADD_VIDEOS).into();
+    }
+    let source = match db.get_video_resolved(video).await? {
+        Some(v) => v,
+        None => return ApiResponder::Err(ClipError::VideoNotFound.into()),
+    };
+    // the clip is added to the source's game, so the user must be able to upload there
+    if !source.user_authorized(Some(&user), &db).await? {
+        return ApiResponder::Err(ClipError::VideoNotFound.into());
+    }
+    if !db.get_user_games_ids(&user).await?.contains(&source.game) {
+        return ApiResponder::Err(UploadError::GameNotFound.into());
+    }
+
+    let form = form.into_inner();
+    let file = source.file.as_ref().unwrap_right();
+    let duration = file.duration.unwrap_or(0.);
+    if !form.start.is_finite() || !form.end.is_finite() || form.start < 0. || form.end <= form.start || form.end > duration {
+        return ApiResponder::Err(ClipError::InvalidRange.into());
+    }
+
+    // ffmpeg's output can contain server paths, only the log gets it
+    let path = cut(file, form.start, form.end).await.map_err(|e| {
+        log::error!("error while cutting a clip of video {}: {}", source.id, e);
+        ClipError::CutError
+    })?;
+    let public = form.public.unwrap_or(source.public);
+    let mut clip = match ingest(&db, &user, &source.game, form.name, public, IngestSource::Path(&path)).await {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = std::fs::remove_file(&path);
+            return ApiResponder::Err(e);
+        }
+    };
+    let provenance = ClipSource {
+        video: source.id.clone(),
+        start: form.start,
+        end: form.end,
+    };
+    db.set_clip_source(&clip.id, &provenance).await?;
+    clip.clip = Some(provenance);
+    clip.into()
+}
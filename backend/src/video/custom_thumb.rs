@@ -0,0 +1,216 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use rocket::{data::{Limits, ToByteUnit}, form::Form, fs::TempFile, serde::json::Json};
+use rocket_db_pools::mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+use crate::{authentication::{AuthenticationError, UserGuard}, db::DBWrapper, response::{ApiError, ApiErrorType, ApiResponder, ApiResponse}, storage::STORAGE, tools::Tool, user::{Permissions, User}};
+
+use super::file::{extract_frame, VideoFile};
+use super::Video;
+
+// uploaded images are scaled down to this width
+const MAX_WIDTH: u32 = 1280;
+const MAX_IMAGE_SIZE: u64 = 10 * 1024 * 1024;
+const IMAGE_CODECS: &[&str] = &["mjpeg", "png", "webp", "bmp"];
+// demuxers allowed to read uploaded images
+const IMAGE_FORMATS: &str = "image2,jpeg_pipe,png_pipe,webp_pipe,bmp_pipe";
+// room for the other fields of the multipart form
+const FORM_OVERHEAD: u64 = 64 * 1024;
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum ThumbError {
+    VideoNotFound,
+    NoVideoStream,
+    InvalidTimestamp,
+    InvalidImage(&'static str),
+    ExtractionError,
+}
+
+impl ApiErrorType for ThumbError {
+    fn ty(&self) -> &'static str {
+        match self {
+            Self::VideoNotFound => "video_not_found",
+            Self::NoVideoStream => "no_video_stream",
+            Self::InvalidTimestamp => "invalid_timestamp",
+            Self::InvalidImage(_) => "invalid_image",
+            Self::ExtractionError => "extraction_error",
+        }
+    }
+
+    fn status(&self) -> rocket::http::Status {
+        match self {
+            Self::VideoNotFound => rocket::http::Status::NotFound,
+            Self::NoVideoStream => rocket::http::Status::BadRequest,
+            Self::InvalidTimestamp => rocket::http::Status::BadRequest,
+            Self::InvalidImage(_) => rocket::http::Status::BadRequest,
+            Self::ExtractionError => rocket::http::Status::InternalServerError,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::VideoNotFound => "Video not found".to_string(),
+            Self::NoVideoStream => "Video doesn't have a video stream".to_string(),
+            Self::InvalidTimestamp => "Timestamp is outside of the video".to_string(),
+            Self::InvalidImage(s) => format!("Invalid image: {}", s),
+            Self::ExtractionError => "Error while generating thumbnail".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct ThumbResponse {
+    version: u32,
+}
+
+impl ApiResponse for ThumbResponse {}
+
+// fetches the video, checking that the user is allowed to change it
+async fn modifiable_video(video: &str, user: &User, db: &DBWrapper) -> Result<Video, ApiError> {
+    let video = db.get_video_resolved(video).await?.ok_or(ThumbError::VideoNotFound)?;
+    let user_games = db.get_user_games_ids(user).await?;
+    if video.user_can_modify(user, &user_games) {
+        Ok(video)
+    } else {
+        Err(AuthenticationError::InsufficientPermissions(Permissions::MODIFY_VIDEO_OTHERS).into())
+    }
+}
+
+// the new thumbnail is written aside and then moved, so that failures don't remove the current one.
+//   it belongs to the video, other videos sharing the file keep theirs
+async fn replace(video: &Video, tmp: PathBuf, db: &DBWrapper) -> Result<ThumbResponse, ApiError> {
+    if let Err(e) = STORAGE.put(&video.thumb_key(), &tmp).await {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    let version = db.bump_thumb_version(video).await?;
+    Ok(ThumbResponse { version })
+}
+
+// unique for each request
+fn tmp_path() -> PathBuf {
+    VideoFile::thumb_work_path(&ObjectId::new().to_hex())
+}
+
+// the tool's output can contain server paths, only the log gets it
+fn extraction_failed(video: &Video, tmp: &Path, e: impl Display) -> ThumbError {
+    log::error!("error while generating thumbnail for video {}: {}", video.id, e);
+    let _ = std::fs::remove_file(tmp);
+    ThumbError::ExtractionError
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TimestampForm {
+    // seconds from the start of the video
+    timestamp: f64,
+}
+
+#[post("/<video>/thumb", data = "<form>", format = "json")]
+pub(crate) async fn from_timestamp(video: &str, form: Json<TimestampForm>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<ThumbResponse> {
+    let user = user?.user;
+    let video = match modifiable_video(video, &user, &db).await {
+        Ok(v) => v,
+        Err(e) => return ApiResponder::Err(e),
+    };
+    let file = video.file.as_ref().unwrap_right();
+    if !file.has_video() {
+        return ApiResponder::Err(ThumbError::NoVideoStream.into());
+    }
+    let t = form.timestamp;
+    if !t.is_finite() || t < 0. || file.duration.is_some_and(|d| t > d) {
+        return ApiResponder::Err(ThumbError::InvalidTimestamp.into());
+    }
+    let tmp = tmp_path();
+    let source = file.local_file().await.map_err(|e| extraction_failed(&video, &tmp, e))?;
+    extract_frame(source.path(), t, &tmp).await.map_err(|e| extraction_failed(&video, &tmp, e))?;
+    match replace(&video, tmp, &db).await {
+        Ok(r) => r.into(),
+        Err(e) => ApiResponder::Err(e),
+    }
+}
+
+// rocket's default form limits are lower than the largest image, they are raised to fit it
+pub(crate) fn limits(limits: Limits) -> Limits {
+    let file = limits.get("file").unwrap_or_default().max(MAX_IMAGE_SIZE.bytes());
+    let form = limits.get("data-form").unwrap_or_default().max((MAX_IMAGE_SIZE + FORM_OVERHEAD).bytes());
+    limits.limit("file", file).limit("data-form", form)
+}
+
+#[derive(FromForm)]
+pub(crate) struct ImageForm<'r> {
+    image: TempFile<'r>,
+}
+
+#[post("/<video>/thumb", data = "<form>", format = "multipart/form-data", rank = 2)]
+pub(crate) async fn upload(video: &str, form: Form<ImageForm<'_>>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<ThumbResponse> {
+    let user = user?.user;
+    let video = match modifiable_video(video, &user, &db).await {
+        Ok(v) => v,
+        Err(e) => return ApiResponder::Err(e),
+    };
+    if form.image.len() > MAX_IMAGE_SIZE {
+        return ApiResponder::Err(ThumbError::InvalidImage("file too large").into());
+    }
+    let source = match form.image.path() {
+        Some(p) => p,
+        None => return ApiResponder::Err(ThumbError::InvalidImage("empty file").into()),
+    };
+
+    // validate: must be a single still image with sane dimensions
+    let probed = Tool::Ffprobe.command()
+        .args(["-v", "error", "-select_streams", "v:0", "-show_entries", "stream=codec_name,width,height", "-of", "csv=p=0"])
+        .untrusted_input(IMAGE_FORMATS)
+        .arg(source)
+        .run()
+        .await
+        .map_err(|_| ThumbError::InvalidImage("unreadable file"))?;
+    let probed = String::from_utf8_lossy(&probed);
+    let fields = probed.trim().split(',').collect::<Vec<_>>();
+    if fields.len() != 3 || !IMAGE_CODECS.contains(&fields[0]) {
+        return ApiResponder::Err(ThumbError::InvalidImage("unsupported format").into());
+    }
+    match (fields[1].parse::<u32>(), fields[2].parse::<u32>()) {
+        (Ok(w), Ok(h)) if w > 0 && h > 0 && w <= 8192 && h <= 8192 => {}
+        _ => return ApiResponder::Err(ThumbError::InvalidImage("unsupported dimensions").into()),
+    }
+
+    // resize and re-encode, dropping anything else the file could carry
+    let tmp = tmp_path();
+    Tool::Ffmpeg.command()
+        .args(["-y", "-v", "error"])
+        .untrusted_input(IMAGE_FORMATS)
+        .arg("-i")
+        .arg(source)
+        .arg("-vf")
+        .arg(format!("scale='min({},iw)':-2", MAX_WIDTH))
+        .args(["-frames:v", "1", "-q:v", "3", "-map_metadata", "-1"])
+        .arg(&tmp)
+        .timeout(crate::CONFIG.tools.probe_timeout)
+        .run()
+        .await
+        .map_err(|e| extraction_failed(&video, &tmp, e))?;
+    match replace(&video, tmp, &db).await {
+        Ok(r) => r.into(),
+        Err(e) => ApiResponder::Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_fit_largest_image() {
+        let raised = limits(Limits::default());
+        assert!(raised.get("file").unwrap() >= MAX_IMAGE_SIZE.bytes());
+        assert!(raised.get("data-form").unwrap() > MAX_IMAGE_SIZE.bytes());
+        // deployments allowing large video uploads keep their limits
+        let large = Limits::default().limit("file", 2.gibibytes()).limit("data-form", 2.gibibytes());
+        let kept = limits(large);
+        assert_eq!(kept.get("file"), Some(2.gibibytes()));
+        assert_eq!(kept.get("data-form"), Some(2.gibibytes()));
+    }
+}
@@ -0,0 +1,332 @@
+use rocket::{http::{uri::fmt::{Query, UriDisplay}, ContentType, MediaType}, request::{FromRequest, Outcome}, response::content::RawHtml};
+use rocket_db_pools::mongodb;
+use serde::Serialize;
+
+use crate::{db::DBWrapper, response::{ApiErrorType, ApiResponder, ApiResponse}, CONFIG};
+
+use super::{file::VideoFile, Video};
+
+// user agents of the link previewers that don't ask for html explicitly
+const UNFURLERS: &[&str] = &["discordbot", "telegrambot", "twitterbot", "slackbot", "facebookexternalhit", "whatsapp", "embedly"];
+// used when the file's resolution is unknown
+const DEFAULT_SIZE: (u32, u32) = (1280, 720);
+
+// whether the client wants a page rather than the media itself: browsers and chat apps unfurling a link.
+//   players asking for `*/*` get the stream
+pub(crate) struct WantsPage(pub(crate) bool);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for WantsPage {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        let html = request
+            .accept()
+            .is_some_and(|a| a.iter().any(|m| m.media_type() == &MediaType::HTML));
+        let unfurler = request
+            .headers()
+            .get_one("User-Agent")
+            .map(|ua| ua.to_lowercase())
+            .is_some_and(|ua| UNFURLERS.iter().any(|u| ua.contains(u)));
+        Outcome::Success(Self(html || unfurler))
+    }
+}
+
+// absolute address of the site, without trailing slash
+pub(crate) struct BaseUrl(String);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for BaseUrl {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        if let Some(ref url) = CONFIG.public_url {
+            return Outcome::Success(Self(url.trim_end_matches('/').to_string()));
+        }
+        let headers = request.headers();
+        // anyone can send forwarded headers, they are only taken from the configured proxies
+        let proxied = request.remote().is_some_and(|r| CONFIG.trusted_proxies.contains(&r.ip()));
+        let forwarded = |h| if proxied { headers.get_one(h) } else { None };
+        let scheme = forwarded("X-Forwarded-Proto").unwrap_or("http");
+        let host = forwarded("X-Forwarded-Host").or(headers.get_one("Host")).unwrap_or_default();
+        Outcome::Success(Self(format!("{}://{}", scheme, host)))
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// urls and details of a shared video, `file` is the one to stream
+struct Shared<'a> {
+    base: &'a str,
+    video: &'a Video,
+    file: &'a VideoFile,
+}
+
+impl Shared<'_> {
+    fn title(&self) -> &str {
+        self.video.name.as_deref().unwrap_or(&self.video.id)
+    }
+
+    fn page_url(&self) -> String {
+        format!("{}/share/{}", self.base, self.video.id)
+    }
+
+    fn stream_url(&self) -> String {
+        format!("{}/share/{}?raw=true", self.base, self.video.id)
+    }
+
+    fn thumb_url(&self) -> String {
+        format!("{}/api/video/{}/thumb?v={}", self.base, self.video.id, self.video.thumb().1)
+    }
+
+    fn mime(&self) -> String {
+        ContentType::from_extension(&self.file.format.to_string())
+            .unwrap_or(ContentType::Binary)
+            .to_string()
+    }
+
+    fn size(&self) -> (u32, u32) {
+        self.file.resolution().unwrap_or(DEFAULT_SIZE)
+    }
+
+    fn page(&self) -> String {
+        let title = escape(self.title());
+        let description = escape(self.video.description.as_deref().unwrap_or_default());
+        let (page, stream, thumb) = (escape(&self.page_url()), escape(&self.stream_url()), escape(&self.thumb_url()));
+        let oembed = escape(&format!("{}/share/oembed?url={}", self.base, &self.page_url() as &dyn UriDisplay<Query>));
+        let mime = self.mime();
+        let (width, height) = self.size();
+        let duration = self.file.duration.map(|d| format!(
+            "\n    <meta property=\"video:duration\" content=\"{}\">", d.round() as u64
+        )).unwrap_or_default();
+        format!(r#"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="utf-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+    <title>{title}</title>
+    <meta name="description" content="{description}">
+    <meta property="og:site_name" content="MeTube">
+    <meta property="og:type" content="video.other">
+    <meta property="og:title" content="{title}">
+    <meta property="og:description" content="{description}">
+    <meta property="og:url" content="{page}">
+    <meta property="og:image" content="{thumb}">
+    <meta property="og:video" content="{stream}">
+    <meta property="og:video:secure_url" content="{stream}">
+    <meta property="og:video:type" content="{mime}">
+    <meta property="og:video:width" content="{width}">
+    <meta property="og:video:height" content="{height}">{duration}
+    <meta name="twitter:card" content="player">
+    <meta name="twitter:title" content="{title}">
+    <meta name="twitter:image" content="{thumb}">
+    <meta name="twitter:player" content="{page}">
+    <meta name="twitter:player:stream" content="{stream}">
+    <meta name="twitter:player:stream:content_type" content="{mime}">
+    <meta name="twitter:player:width" content="{width}">
+    <meta name="twitter:player:height" content="{height}">
+    <link rel="alternate" type="application/json+oembed" href="{oembed}" title="{title}">
+    <style>
+        body {{ margin: 0; background: #000; color: #eee; font-family: sans-serif; }}
+        video {{ display: block; width: 100%; max-height: 90vh; }}
+        h1 {{ font-size: 1.2em; margin: 0.5em; }}
+    </style>
+</head>
+<body>
+    <video src="{stream}" poster="{thumb}" controls playsinline preload="metadata"></video>
+    <h1>{title}</h1>
+</body>
+</html>
+"#)
+    }
+}
+
+// the file that is streamed for a resolved video
+async fn converted_file(original: &VideoFile, db: &DBWrapper) -> Result<Option<VideoFile>, mongodb::error::Error> {
+    match original.converted {
+        Some(ref c) => db.get_video_file(c).await,
+        None => Ok(None),
+    }
+}
+
+// landing page of `/share/<video>`, for videos already checked to be public
+pub(crate) async fn page(video: &Video, base: &BaseUrl, db: &DBWrapper) -> Result<RawHtml<String>, mongodb::error::Error> {
+    let original = video.file.as_ref().unwrap_right();
+    let converted = converted_file(original, db).await?;
+    let shared = Shared {
+        base: &base.0,
+        video,
+        file: converted.as_ref().unwrap_or(original),
+    };
+    Ok(RawHtml(shared.page()))
+}
+
+// asks for the password of a share link, the form is posted back to the link
+pub(crate) fn password_page(failed: bool) -> RawHtml<String> {
+    let error = if failed { "\n        <p>Wrong password</p>" } else { "" };
+    RawHtml(format!(r#"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="utf-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+    <meta name="robots" content="noindex">
+    <title>MeTube</title>
+    <style>
+        body {{ margin: 0; background: #000; color: #eee; font-family: sans-serif; }}
+        form {{ margin: 2em auto; max-width: 20em; }}
+        input {{ display: block; width: 100%; margin-bottom: 0.5em; }}
+    </style>
+</head>
+<body>
+    <form method="post">
+        <p>This video is protected by a password</p>{error}
+        <input type="password" name="password" autofocus required>
+        <input type="submit" value="Watch">
+    </form>
+</body>
+</html>
+"#))
+}
+
+#[derive(Serialize)]
+pub(crate) struct OEmbed {
+    version: &'static str,
+    #[serde(rename = "type")]
+    ty: &'static str,
+    title: String,
+    provider_name: &'static str,
+    provider_url: String,
+    thumbnail_url: String,
+    html: String,
+    width: u32,
+    height: u32,
+}
+
+impl ApiResponse for OEmbed {}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum EmbedError {
+    VideoNotFound,
+    UnsupportedFormat,
+}
+
+impl ApiErrorType for EmbedError {
+    fn ty(&self) -> &'static str {
+        match self {
+            Self::VideoNotFound => "video_not_found",
+            Self::UnsupportedFormat => "unsupported_format",
+        }
+    }
+
+    fn status(&self) -> rocket::http::Status {
+        match self {
+            Self::VideoNotFound => rocket::http::Status::NotFound,
+            Self::UnsupportedFormat => rocket::http::Status::NotImplemented,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::VideoNotFound => "Video not found".to_string(),
+            Self::UnsupportedFormat => "Only the json format is supported".to_string(),
+        }
+    }
+}
+
+// id of the video in a share page url
+fn shared_video_id(url: &str) -> Option<&str> {
+    let (_, rest) = url.split_once("/share/")?;
+    let id = rest.split(['?', '#', '/']).next()?;
+    (!id.is_empty()).then_some(id)
+}
+
+// scales the size down to fit the consumer's bounds, keeping the aspect ratio
+fn fit((width, height): (u32, u32), maxwidth: Option<u32>, maxheight: Option<u32>) -> (u32, u32) {
+    let scale = [maxwidth.map(|m| m as f64 / width as f64), maxheight.map(|m| m as f64 / height as f64)]
+        .into_iter()
+        .flatten()
+        .fold(1f64, f64::min);
+    ((width as f64 * scale).round() as u32, (height as f64 * scale).round() as u32)
+}
+
+// oembed endpoint for share pages, see https://oembed.com
+#[get("/oembed?<url>&<format>&<maxwidth>&<maxheight>")]
+pub(crate) async fn oembed(url: &str, format: Option<&str>, maxwidth: Option<u32>, maxheight: Option<u32>, base: BaseUrl, db: DBWrapper) -> ApiResponder<OEmbed> {
+    if format.is_some_and(|f| f != "json") {
+        return ApiResponder::Err(EmbedError::UnsupportedFormat.into());
+    }
+    let video = match shared_video_id(url) {
+        Some(id) => db.get_video_resolved(id).await?,
+        None => None,
+    };
+    let video = match video {
+        Some(v) if v.public => v,
+        _ => return ApiResponder::Err(EmbedError::VideoNotFound.into()),
+    };
+    let original = video.file.as_ref().unwrap_right();
+    let converted = converted_file(original, &db).await?;
+    let shared = Shared {
+        base: &base.0,
+        video: &video,
+        file: converted.as_ref().unwrap_or(original),
+    };
+    let (width, height) = fit(shared.size(), maxwidth, maxheight);
+    let html = format!(
+        r#"<video src="{}" poster="{}" width="{}" height="{}" controls playsinline preload="metadata"></video>"#,
+        escape(&shared.stream_url()), escape(&shared.thumb_url()), width, height,
+    );
+    OEmbed {
+        version: "1.0",
+        ty: "video",
+        title: shared.title().to_string(),
+        provider_name: "MeTube",
+        provider_url: base.0.clone(),
+        thumbnail_url: shared.thumb_url(),
+        html,
+        width,
+        height,
+    }.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_special_characters() {
+        assert_eq!(escape(r#"<a href="x">Tom & Jerry's</a>"#), "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;");
+        // already escaped text is escaped again, not left alone
+        assert_eq!(escape("&amp;"), "&amp;amp;");
+        assert_eq!(escape("plain"), "plain");
+    }
+
+    #[test]
+    fn shared_video_id_from_url() {
+        assert_eq!(shared_video_id("https://tube.example/share/abc"), Some("abc"));
+        assert_eq!(shared_video_id("https://tube.example/share/abc?t=10"), Some("abc"));
+        assert_eq!(shared_video_id("https://tube.example/share/abc#comments"), Some("abc"));
+        assert_eq!(shared_video_id("https://tube.example/share/abc/"), Some("abc"));
+        assert_eq!(shared_video_id("https://tube.example/share/"), None);
+        assert_eq!(shared_video_id("https://tube.example/share/?t=10"), None);
+        assert_eq!(shared_video_id("https://tube.example/video/abc"), None);
+    }
+
+    #[test]
+    fn fit_within_bounds() {
+        assert_eq!(fit((1920, 1080), None, None), (1920, 1080));
+        assert_eq!(fit((1920, 1080), Some(640), None), (640, 360));
+        assert_eq!(fit((1920, 1080), None, Some(540)), (960, 540));
+        // the tighter bound wins
+        assert_eq!(fit((1920, 1080), Some(640), Some(180)), (320, 180));
+        // never scaled up
+        assert_eq!(fit((1280, 720), Some(3840), None), (1280, 720));
+        assert_eq!(fit((1280, 720), Some(3840), Some(2160)), (1280, 720));
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+
+use rocket::futures::TryStreamExt;
+use rocket_db_pools::mongodb::{self, bson::{doc, Document}};
+use serde::Serialize;
+
+use crate::{db::DBWrapper, response::ApiErrorType, user::User};
+
+use super::{file::VideoFile, Either, Video};
+
+// related data embedded in video responses on request, through `expand=file,game,likes`
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Expand {
+    // the video file, the converted one when there is one
+    file: bool,
+    // name of the game
+    game: bool,
+    // like count and whether the user liked the video
+    likes: bool,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum ExpandError {
+    UnknownField(String),
+}
+
+impl ApiErrorType for ExpandError {
+    fn ty(&self) -> &'static str {
+        match self {
+            Self::UnknownField(_) => "unknown_expand_field",
+        }
+    }
+
+    fn status(&self) -> rocket::http::Status {
+        match self {
+            Self::UnknownField(_) => rocket::http::Status::BadRequest,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::UnknownField(f) => format!("Unknown expand field: {}, expected file, game or likes", f),
+        }
+    }
+}
+
+impl Expand {
+    // comma separated list of fields
+    pub(crate) fn parse(s: Option<&str>) -> Result<Self, ExpandError> {
+        let mut expand = Self::default();
+        for field in s.unwrap_or_default().split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            match field {
+                "file" => expand.file = true,
+                "game" => expand.game = true,
+                "likes" => expand.likes = true,
+                f => return Err(ExpandError::UnknownField(f.to_string())),
+            }
+        }
+        Ok(expand)
+    }
+
+    fn is_empty(&self) -> bool {
+        !(self.file || self.game || self.likes)
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct Expanded {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    game_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    likes: Option<i32>,
+    // whether the requesting user liked the video
+    #[serde(skip_serializing_if = "Option::is_none")]
+    liked: Option<bool>,
+}
+
+impl DBWrapper {
+    // resolves the requested fields of all the given videos at once, videos are updated in place
+    pub(crate) async fn expand_videos(&self, videos: &mut [Video], expand: Expand, user: &User) -> Result<Vec<Expanded>, mongodb::error::Error> {
+        if expand.is_empty() {
+            return Ok(videos.iter().map(|_| Expanded::default()).collect());
+        }
+        let ids = videos.iter().map(|v| v.id.clone()).collect::<Vec<_>>();
+        let mut pipeline = vec![doc! {"$match": {"_id": {"$in": &ids}}}];
+        let mut project = doc! {"_id": 1};
+        if expand.file {
+            pipeline.extend([
+                doc! {"$lookup": {
+                    "from": Self::VIDEO_FILES,
+                    "localField": "file",
+                    "foreignField": "_id",
+                    "as": "_file",
+                }},
+                doc! {"$lookup": {
+                    "from": Self::VIDEO_FILES,
+                    "localField": "_file.converted",
+                    "foreignField": "_id",
+                    "as": "_converted",
+                }},
+            ]);
+            project.insert("file", doc! {"$ifNull": [
+                {"$arrayElemAt": ["$_converted", 0]},
+                {"$arrayElemAt": ["$_file", 0]},
+            ]});
+        }
+        if expand.game {
+            pipeline.push(doc! {"$lookup": {
+                "from": Self::GAMES,
+                "localField": "game",
+                "foreignField": "_id",
+                "as": "_game",
+            }});
+            project.insert("game_name", doc! {"$arrayElemAt": ["$_game.name", 0]});
+        }
+        if expand.likes {
+            pipeline.push(doc! {"$lookup": {
+                "from": Self::LIKES,
+                "localField": "_id",
+                "foreignField": "video",
+                "pipeline": [{"$project": {"user": 1, "_id": 0}}],
+                "as": "_likes",
+            }});
+            project.insert("likes", doc! {"$size": "$_likes"});
+            project.insert("liked", doc! {"$in": [&user.username, "$_likes.user"]});
+        }
+        pipeline.push(doc! {"$project": project});
+
+        let mut docs: HashMap<String, Document> = self
+            .collection::<Document>(Self::VIDEOS)
+            .aggregate(pipeline, None)
+            .await?
+            .map_ok(|d| (d.get_str("_id").unwrap().to_string(), d))
+            .try_collect()
+            .await?;
+        Ok(videos
+            .iter_mut()
+            .map(|v| {
+                let Some(mut d) = docs.remove(&v.id) else {
+                    return Expanded::default();
+                };
+                if let Ok(file) = d.get_document("file") {
+                    v.file = Either::Right(mongodb::bson::from_document::<VideoFile>(file.clone()).unwrap());
+                }
+                Expanded {
+                    game_name: d.remove("game_name").and_then(|n| n.as_str().map(str::to_string)),
+                    likes: d.get_i32("likes").ok(),
+                    liked: d.get_bool("liked").ok(),
+                }
+            })
+            .collect())
+    }
+}
@@ -2,11 +2,17 @@ use std::fmt::Display;
 use std::path::PathBuf;
 use std::{collections::HashMap, path::Path};
 
+use rocket::tokio::{fs::File, io::AsyncReadExt};
 use rocket_db_pools::mongodb::bson::{doc, oid::ObjectId};
+use sha1::{Digest, Sha1};
 use serde::{Serialize, Deserialize};
 
 use crate::config::CONFIG;
+use crate::storage::{LocalFile, STORAGE};
+use crate::tools::{Tool, ToolError};
 
+use super::hls::Rendition;
+use super::sprite::SpriteSheet;
 use super::UploadError;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -75,28 +81,120 @@ impl From<&str> for Format {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
-enum CodecType {
+pub(crate) enum CodecType {
     Video,
     Audio,
     Subtitle,
     Data,
+    Attachment,
+    #[serde(other)]
+    Unknown,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
+struct ProbedDisposition {
+    #[serde(default)]
+    default: u8,
+}
+
+#[derive(Deserialize, Debug)]
 struct ProbedStream {
     index: u32,
+    // missing on some attachment streams
+    #[serde(default)]
     codec_name: String,
     profile: Option<String>,
     codec_type: CodecType,
-    #[serde(rename = "codec_tag_string")]
-    codec_tag: String,
-    // #[serde(deserialize_with = "deserialize_string_float")]
     duration: Option<String>,
-    // save all tags into a hashmap
-    #[serde(flatten)]
-    tags: Option<HashMap<String, serde_json::Value>>
+    width: Option<u32>,
+    height: Option<u32>,
+    pix_fmt: Option<String>,
+    avg_frame_rate: Option<String>,
+    r_frame_rate: Option<String>,
+    bit_rate: Option<String>,
+    sample_rate: Option<String>,
+    channels: Option<u32>,
+    channel_layout: Option<String>,
+    #[serde(default)]
+    disposition: ProbedDisposition,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+// ffprobe gives frame rates as fractions, "0/0" when unknown
+fn parse_rate(rate: &str) -> Option<f64> {
+    let (n, d) = rate.split_once('/')?;
+    let (n, d) = (n.parse::<f64>().ok()?, d.parse::<f64>().ok()?);
+    (n > 0. && d > 0.).then(|| n / d)
+}
+
+// matroska stores per stream durations as a `HH:MM:SS.nnnnnnnnn` tag
+fn parse_tag_duration(d: &str) -> Option<f64> {
+    let mut parts = d.splitn(3, ':');
+    let (h, m, s) = (parts.next()?, parts.next()?, parts.next()?);
+    Some(h.parse::<f64>().ok()? * 3600. + m.parse::<f64>().ok()? * 60. + s.parse::<f64>().ok()?)
+}
+
+impl ProbedStream {
+    fn duration(&self) -> Option<f64> {
+        self.duration.as_ref().and_then(|v| v.parse::<f64>().ok())
+            .or_else(|| self.tags.get("DURATION").and_then(|d| parse_tag_duration(d)))
+    }
+
+    // tag keys differ in case between containers
+    fn tag(&self, key: &str) -> Option<String> {
+        self.tags.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v.clone())
+    }
+}
+
+// metadata of a single track, as stored in `VideoFile.streams`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct StreamInfo {
+    pub(crate) index: u32,
+    pub(crate) kind: CodecType,
+    pub(crate) codec: String,
+    pub(crate) profile: Option<String>,
+    pub(crate) duration: Option<f64>,
+    // bits per second, not always known for streams in matroska files
+    pub(crate) bitrate: Option<u64>,
+    pub(crate) language: Option<String>,
+    pub(crate) title: Option<String>,
+    pub(crate) default: bool,
+    // video only
+    pub(crate) width: Option<u32>,
+    pub(crate) height: Option<u32>,
+    pub(crate) frame_rate: Option<f64>,
+    pub(crate) pixel_format: Option<String>,
+    // audio only
+    pub(crate) sample_rate: Option<u32>,
+    pub(crate) channels: Option<u32>,
+    pub(crate) channel_layout: Option<String>,
+}
+
+impl From<&ProbedStream> for StreamInfo {
+    fn from(s: &ProbedStream) -> Self {
+        Self {
+            index: s.index,
+            kind: s.codec_type,
+            codec: s.codec_name.clone(),
+            profile: s.profile.clone(),
+            duration: s.duration(),
+            bitrate: s.bit_rate.as_ref().and_then(|b| b.parse().ok()).or_else(|| s.tag("BPS").and_then(|b| b.parse().ok())),
+            // "und" is ffmpeg's way of saying there is no language
+            language: s.tag("language").filter(|l| l != "und"),
+            title: s.tag("title"),
+            default: s.disposition.default != 0,
+            width: s.width,
+            height: s.height,
+            frame_rate: s.avg_frame_rate.as_deref().and_then(parse_rate).or_else(|| s.r_frame_rate.as_deref().and_then(parse_rate)),
+            pixel_format: s.pix_fmt.clone(),
+            sample_rate: s.sample_rate.as_ref().and_then(|r| r.parse().ok()),
+            channels: s.channels,
+            channel_layout: s.channel_layout.clone(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -108,6 +206,25 @@ pub(crate) struct ProbedFormat {
     duration: Option<String>,
 }
 
+#[derive(Deserialize)]
+struct Probed {
+    streams: Vec<ProbedStream>,
+    format: ProbedFormat,
+}
+
+async fn probe(path: &Path) -> Result<Probed, UploadError> {
+    let probed = Tool::Ffprobe.command()
+        .args(["-v", "error", "-show_streams", "-show_format", "-of", "json"])
+        .arg(path)
+        .run()
+        .await
+        // the tool's output can contain server paths, only the log gets it
+        .map_err(|e| { log::warn!("error while probing {}: {}", path.display(), e); UploadError::ProbeError("ffprobe failed") })?;
+    let probed = String::from_utf8(probed)
+        .map_err(|_| UploadError::ProbeError("ffprobe output format"))?;
+    serde_json::from_str(&probed).map_err(|e| { log::error!("error while probing video: {:?}", e); UploadError::ProbeError("deserializing ffprobe output") })
+}
+
 fn deserialize_string_usize<'de, D>(deserializer: D) -> Result<usize, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -120,125 +237,210 @@ where
 pub(crate) struct VideoFile {
     #[serde(rename = "_id")]
     pub id: String,
-    duration: Option<f64>,
+    pub(crate) duration: Option<f64>,
     size: Option<usize>,
-    audio_codec: AudioCodec,
-    video_codec: VideoCodec,
+    pub(super) audio_codec: AudioCodec,
+    pub(super) video_codec: VideoCodec,
     pub(super) format: Format,
+    // sha1 of the file content, used to detect duplicate uploads
+    #[serde(default)]
+    pub(super) hash: Option<String>,
     pub(crate) converted: Option<String>,
+    // available hls renditions, set once segments are generated
+    #[serde(default)]
+    pub(crate) hls: Option<Vec<Rendition>>,
+    // seek bar preview sheet, set once generated
+    #[serde(default)]
+    pub(crate) sprite: Option<SpriteSheet>,
+    // bumped every time the thumbnail changes, clients add it to the thumbnail url to bust caches
+    #[serde(default)]
+    pub(crate) thumb_version: u32,
+    // every track of the file, empty for files probed before this was stored
+    #[serde(default)]
+    pub(crate) streams: Vec<StreamInfo>,
 }
 
 
+pub(super) async fn probe_resolution(path: &Path) -> Result<(u32, u32), String> {
+    let out = Tool::Ffprobe.command()
+        .args(["-v", "error", "-select_streams", "v:0", "-show_entries", "stream=width,height", "-of", "csv=p=0"])
+        .arg(path)
+        .run()
+        .await
+        .map_err(|e| e.to_string())?;
+    let out = String::from_utf8_lossy(&out);
+    let mut dims = out.trim().split(',').map(|d| d.parse::<u32>());
+    match (dims.next(), dims.next()) {
+        (Some(Ok(w)), Some(Ok(h))) => Ok((w, h)),
+        _ => Err("file has no video stream".to_string()),
+    }
+}
+
+pub(super) async fn extract_frame(source: &Path, at: f64, target: &Path) -> Result<(), ToolError> {
+    Tool::Ffmpeg.command()
+        .args(["-y", "-v", "error", "-ss"])
+        .arg(at.to_string())
+        .arg("-i")
+        .arg(source)
+        .args(["-frames:v", "1"])
+        .arg(target)
+        .timeout(CONFIG.tools.probe_timeout)
+        .run()
+        .await
+        .map(|_| ())
+}
+
+pub(super) async fn hash_file(path: &Path) -> Result<String, std::io::Error> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha1::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
 impl VideoFile {
     pub(super) async fn from_path(path: &Path) -> Result<VideoFile, UploadError> {
-        let proc = std::process::Command::new("ffprobe")
-            .arg("-v")
-            .arg("quiet")
-            .arg("-show_streams")
-            .arg("-show_format")
-            .arg("-of")
-            .arg("json")
-            .arg(path)
-            .output()
-            .map_err(|_| UploadError::ProbeError("ffprobe process"))?;
-        if proc.status.success() {
-            let probed = String::from_utf8(proc.stdout)
-                .map_err(|_| UploadError::ProbeError("ffprobe output format"))?;
-
-            #[derive(Deserialize)]
-            struct Probed {
-                streams: Vec<ProbedStream>,
-                format: ProbedFormat,
-            }
-            let probed: Probed = serde_json::from_str(&probed).map_err(|e| { log::error!("error while probing video: {:?}", e); UploadError::ProbeError("deserializing ffprobe output") })?;
-            let streams = probed.streams;
-            // this purposelly keeps the first audio and video stream
-            let a_stream = streams.iter()
-                .find(|s| matches!(s.codec_type, CodecType::Audio))
-                .map(|s| AudioCodec::from(s.codec_name.as_str()));
-            let v_stream = streams.iter()
-                .find(|s| matches!(s.codec_type, CodecType::Video))
-                .map(|s| VideoCodec::from(s.codec_name.as_str()));
-
-            if a_stream.is_none() && v_stream.is_none() {
-                return Err(UploadError::FormatError("uploaded file doens't contain audio either video nor audio streams"));
-            }
+        let file = Self::probe_path(path).await?;
+        // if there is video then create thumbnail
+        if file.has_video() {
+            // get video stream length
+            let vlength = file.streams.iter()
+                .find(|s| matches!(s.kind, CodecType::Video))
+                .and_then(|s| s.duration)
+                .unwrap_or(1.);
 
-            let fduration = probed.format.duration.as_ref().map(|v| v.parse::<f64>().unwrap());
-            let duration = streams.iter()
-                .map(|s| s.duration
-                    .as_ref()
-                    .map(|v| v.parse::<f64>().unwrap())
-                    .or(s.tags.as_ref().and_then(|t| t.get("DURATION").and_then(|v| v.as_f64()))))
-                .fold(fduration, |acc, d| {
-                    match (acc, d) {
-                        (Some(a), Some(b)) => if a > b { Some(a) } else { Some(b) },
-                        (Some(a), None) => Some(a),
-                        (None, Some(b)) => Some(b),
-                        (None, None) => None,
-                    }
-                });
-
-            let id = ObjectId::new().to_hex();
-            // if there is video then create thumbnail
-            if v_stream.is_some() {
-                // get video stream length
-                let vlength = streams.iter()
-                    .find(|s| matches!(s.codec_type, CodecType::Video))
-                    .and_then(|s| s.duration.as_ref().map(|v| v.parse::<f64>().unwrap()))
-                    .unwrap_or(1.);
-
-                let proc = std::process::Command::new("ffmpeg")
-                    .arg("-y")
-                    .arg("-ss")
-                    .arg((vlength * 0.2).to_string())
-                    .arg("-i")
-                    .arg(path)
-                    .arg("-frames:v")
-                    .arg("1")
-                    .arg(Path::new(&CONFIG.video_storage).join("thumbs").join(format!("{}.jpg", id)))
-                    .output();
-                match proc {
-                    Err(e) => log::error!("failed to create thumbnail for video {}: {}", id, e),
-                    Ok(proc) => {
-                        if !proc.status.success() {
-                            log::error!("failed to create thumbnail for video {}: {}", id, String::from_utf8_lossy(&proc.stderr));
-                        }
-                    }
-                }
+            let thumb = Self::thumb_work_path(&file.id);
+            match extract_frame(path, vlength * 0.2, &thumb).await {
+                Ok(()) => if let Err(e) = STORAGE.put(&Self::thumb_key(&file.id), &thumb).await {
+                    log::error!("failed to store thumbnail for video {}: {}", file.id, e);
+                    let _ = std::fs::remove_file(&thumb);
+                },
+                Err(e) => log::error!("failed to create thumbnail for video {}: {}", file.id, e),
             }
+        }
+        Ok(file)
+    }
+
+    // same as `from_path`, without creating a thumbnail
+    pub(super) async fn probe_path(path: &Path) -> Result<VideoFile, UploadError> {
+        let probed = probe(path).await?;
+        let streams = probed.streams;
+        // this purposelly keeps the first audio and video stream
+        let a_stream = streams.iter()
+            .find(|s| matches!(s.codec_type, CodecType::Audio))
+            .map(|s| AudioCodec::from(s.codec_name.as_str()));
+        let v_stream = streams.iter()
+            .find(|s| matches!(s.codec_type, CodecType::Video))
+            .map(|s| VideoCodec::from(s.codec_name.as_str()));
 
-            Ok(VideoFile {
-                id,
-                duration,
-                size: Some(probed.format.size),
-                audio_codec: a_stream.unwrap_or(AudioCodec::Unk("unknown".to_string())),
-                video_codec: v_stream.unwrap_or(VideoCodec::Unk("unknown".to_string())),
-                format: Format::from(probed.format.format_name.as_str()),
-                converted: None,
-            })
-        } else {
-            let err = String::from_utf8(proc.stderr)
-                .expect("format error on ffprobe output");
-            log::warn!("{}", err);
-            Err(UploadError::ProbeError("ffprobe process"))
+        if a_stream.is_none() && v_stream.is_none() {
+            return Err(UploadError::FormatError("uploaded file doens't contain audio either video nor audio streams"));
         }
+
+        let fduration = probed.format.duration.as_ref().map(|v| v.parse::<f64>().unwrap());
+        let duration = streams.iter()
+            .map(|s| s.duration())
+            .fold(fduration, |acc, d| {
+                match (acc, d) {
+                    (Some(a), Some(b)) => if a > b { Some(a) } else { Some(b) },
+                    (Some(a), None) => Some(a),
+                    (None, Some(b)) => Some(b),
+                    (None, None) => None,
+                }
+            });
+
+        Ok(VideoFile {
+            id: ObjectId::new().to_hex(),
+            duration,
+            size: Some(probed.format.size),
+            audio_codec: a_stream.unwrap_or(AudioCodec::Unk("unknown".to_string())),
+            video_codec: v_stream.unwrap_or(VideoCodec::Unk("unknown".to_string())),
+            format: Format::from(probed.format.format_name.as_str()),
+            hash: None,
+            converted: None,
+            hls: None,
+            sprite: None,
+            thumb_version: 0,
+            streams: streams.iter().map(StreamInfo::from).collect(),
+        })
     }
 
-    pub(crate) fn path(&self) -> PathBuf {
-        PathBuf::from(&CONFIG.video_storage).join(&self.id)
+    // used to backfill `streams` on files stored before it existed
+    pub(super) async fn probe_streams(&self) -> Result<Vec<StreamInfo>, UploadError> {
+        let source = self.local_file().await.map_err(|e| {
+            log::error!("error while reading file {}: {}", self.id, e);
+            UploadError::ProbeError("reading the stored file")
+        })?;
+        let probed = probe(source.path()).await?;
+        Ok(probed.streams.iter().map(StreamInfo::from).collect())
     }
 
-    pub(crate) fn thumb(id: &str) -> Option<PathBuf> {
-        let thumb = Path::new(&CONFIG.video_storage).join("thumbs").join(format!("{}.jpg", id));
-        if thumb.exists() {
-            Some(thumb)
-        } else {
-            None
-        }
+    pub(super) fn has_video(&self) -> bool {
+        !matches!(self.video_codec, VideoCodec::Unk(ref s) if s == "unknown")
+    }
+
+    // whether the file can be played as is by browsers and the flutter player
+    pub(super) fn browser_friendly(&self) -> bool {
+        let audio = match self.audio_codec {
+            AudioCodec::Aac | AudioCodec::Mp3 => true,
+            // no audio stream at all
+            AudioCodec::Unk(ref s) => s == "unknown",
+        };
+        let video = match self.video_codec {
+            VideoCodec::H264 => self.h264_playable(),
+            VideoCodec::Hevc => false,
+            VideoCodec::Unk(ref s) => s == "unknown",
+        };
+        matches!(self.format, Format::Mp4) && audio && video
+    }
+
+    // browsers only decode 8-bit 4:2:0 h264, higher bit depths and chroma need re-encoding.
+    //   files probed before streams were stored are assumed to be playable
+    pub(super) fn h264_playable(&self) -> bool {
+        matches!(self.video_codec, VideoCodec::H264) && self.streams
+            .iter()
+            .find(|s| matches!(s.kind, CodecType::Video))
+            .and_then(|s| s.pixel_format.as_deref())
+            .is_none_or(|f| matches!(f, "yuv420p" | "yuvj420p"))
+    }
+
+    // key of the file in the storage
+    pub(crate) fn key(&self) -> &str {
+        &self.id
+    }
+
+    pub(crate) fn thumb_key(id: &str) -> String {
+        format!("thumbs/{}.jpg", id)
+    }
+
+    // thumbnails are written here, then moved into the storage
+    pub(super) fn thumb_work_path(id: &str) -> PathBuf {
+        Path::new(&CONFIG.video_storage).join("transcode").join(format!("thumb_{}.jpg", id))
+    }
+
+    // the file as seen by ffmpeg, must be kept alive while it's being read
+    pub(crate) async fn local_file(&self) -> Result<LocalFile, std::io::Error> {
+        STORAGE.local_file(self.key()).await
+    }
+
+    // dimensions of the main video track, unknown for files probed before streams were stored
+    pub(crate) fn resolution(&self) -> Option<(u32, u32)> {
+        self.streams
+            .iter()
+            .find(|s| matches!(s.kind, CodecType::Video))
+            .and_then(|s| Some((s.width?, s.height?)))
     }
 
-    pub(crate) fn delete(&self) -> Result<(), std::io::Error> {
-        std::fs::remove_file(self.path())
+    pub(crate) async fn delete(&self) -> Result<(), std::io::Error> {
+        super::hls::delete(&self.id)?;
+        super::sprite::delete(&self.id)?;
+        STORAGE.delete(&Self::thumb_key(&self.id)).await?;
+        STORAGE.delete(self.key()).await
     }
 }
@@ -0,0 +1,133 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::CONFIG;
+use crate::tools::Tool;
+
+use super::file::{probe_resolution, AudioCodec, VideoFile};
+
+pub(crate) const PLAYLIST: &str = "index.m3u8";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Rendition {
+    pub(crate) name: String,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    // average bits per second, measured on the generated segments
+    pub(crate) bandwidth: u64,
+}
+
+pub(crate) fn dir(file: &str) -> PathBuf {
+    Path::new(&CONFIG.video_storage).join("hls").join(file)
+}
+
+// only accept the names that ffmpeg generates, so that path segments can't escape the rendition directory
+pub(crate) fn valid_entry(name: &str) -> bool {
+    name == PLAYLIST || name
+        .strip_prefix("seg_")
+        .and_then(|n| n.strip_suffix(".ts"))
+        .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+}
+
+pub(crate) fn master_playlist(renditions: &[Rendition]) -> String {
+    let mut out = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+    for r in renditions {
+        out.push_str(&format!("#EXT-X-STREAM-INF:BANDWIDTH={},RESOLUTION={}x{}\n{}/{}\n", r.bandwidth, r.width, r.height, r.name, PLAYLIST));
+    }
+    out
+}
+
+fn dir_size(path: &Path) -> u64 {
+    std::fs::read_dir(path)
+        .map(|d| d
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.metadata().ok())
+            .map(|m| m.len())
+            .sum())
+        .unwrap_or(0)
+}
+
+// generates one rendition for each configured height lower than the source, plus the source itself
+pub(super) async fn generate(file: &VideoFile) -> Result<Vec<Rendition>, String> {
+    let source = file.local_file().await.map_err(|e| e.to_string())?;
+    let source = source.path();
+    let (width, height) = probe_resolution(source).await?;
+    let duration = file.duration.unwrap_or(0.).max(1.);
+
+    let mut targets = CONFIG.hls.renditions
+        .iter()
+        .filter(|h| **h < height)
+        .map(|h| (format!("{}p", h), Some(*h)))
+        .collect::<Vec<_>>();
+    targets.push(("source".to_string(), None));
+
+    let root = dir(&file.id);
+    // start from scratch if a previous attempt left something behind
+    if root.exists() {
+        std::fs::remove_dir_all(&root).map_err(|e| e.to_string())?;
+    }
+
+    let mut renditions = vec![];
+    for (name, target) in targets {
+        let out = root.join(&name);
+        std::fs::create_dir_all(&out).map_err(|e| e.to_string())?;
+
+        let mut cmd = Tool::Ffmpeg.command();
+        cmd
+            .args(["-y", "-v", "error", "-i"])
+            .arg(source)
+            .args(["-map", "0:v:0", "-map", "0:a:0?"]);
+        match (target, file.h264_playable()) {
+            // source rendition of an h264 file can be segmented as is
+            (None, true) => { cmd.args(["-c:v", "copy"]); }
+            (target, _) => {
+                if let Some(h) = target {
+                    cmd.args(["-vf", &format!("scale=-2:{}", h)]);
+                }
+                cmd.args(["-c:v", "libx264", "-preset", "veryfast", "-crf", "23", "-pix_fmt", "yuv420p"]);
+                // keyframes aligned to segment boundaries across renditions
+                cmd.args(["-force_key_frames", &format!("expr:gte(t,n_forced*{})", CONFIG.hls.segment_duration)]);
+            }
+        }
+        match file.audio_codec {
+            AudioCodec::Aac => cmd.args(["-c:a", "copy"]),
+            _ => cmd.args(["-c:a", "aac", "-b:a", "128k"]),
+        };
+        cmd
+            .args(["-f", "hls", "-hls_playlist_type", "vod"])
+            .args(["-hls_time", &CONFIG.hls.segment_duration.to_string()])
+            .arg("-hls_segment_filename")
+            .arg(out.join("seg_%05d.ts"))
+            .arg(out.join(PLAYLIST));
+
+        if let Err(e) = cmd.run().await {
+            let _ = std::fs::remove_dir_all(&root);
+            return Err(format!("rendition {}: {}", name, e));
+        }
+
+        let (w, h) = match target {
+            // keep aspect ratio, rounded to an even width like the scale filter does
+            Some(h) => (((width as u64 * h as u64 / height as u64) as u32 + 1) & !1, h),
+            None => (width, height),
+        };
+        renditions.push(Rendition {
+            name,
+            width: w,
+            height: h,
+            bandwidth: (dir_size(&out) as f64 * 8. / duration) as u64,
+        });
+    }
+    // lowest quality first
+    renditions.sort_by_key(|r| r.height);
+    Ok(renditions)
+}
+
+pub(super) fn delete(file: &str) -> Result<(), std::io::Error> {
+    let root = dir(file);
+    if root.exists() {
+        std::fs::remove_dir_all(root)
+    } else {
+        Ok(())
+    }
+}
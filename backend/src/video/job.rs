@@ -0,0 +1,397 @@
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use rocket::futures::TryStreamExt;
+use rocket::{Orbit, Rocket};
+use rocket_db_pools::mongodb::{self, bson::{doc, oid::ObjectId, serde_helpers::chrono_datetime_as_bson_datetime}, options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument}};
+use serde::{Deserialize, Serialize};
+
+use crate::{authentication::{AuthenticationError, IsAdmin, UserGuard}, config::CONFIG, db::DBWrapper, response::{ApiErrorType, ApiResponder, ApiResponse}, storage::STORAGE, tools::Tool};
+
+use super::file::VideoFile;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum JobKind {
+    // convert a file to h264/aac mp4 and link it via `VideoFile.converted`
+    Transcode,
+    // generate hls renditions and store them in `VideoFile.hls`
+    Hls,
+    // generate the seek bar preview sheet and store its layout in `VideoFile.sprite`
+    Sprites,
+    // re-read the file's tracks and store them in `VideoFile.streams`
+    Probe,
+    // extract text subtitle tracks to webvtt
+    Subtitles,
+}
+
+#[derive(Serialize, Deserialize, Debug, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum JobStatus {
+    #[field(value = "pending")]
+    Pending,
+    #[field(value = "running")]
+    Running,
+    #[field(value = "done")]
+    Done,
+    #[field(value = "failed")]
+    Failed,
+}
+
+impl JobStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Running => "running",
+            Self::Done => "done",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Job {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub kind: JobKind,
+    // id of the source VideoFile
+    pub file: String,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub error: Option<String>,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub created: DateTime<Utc>,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub updated: DateTime<Utc>,
+    // jobs are not picked up before this instant, used to delay retries
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub run_after: DateTime<Utc>,
+}
+
+impl Job {
+    fn new(kind: JobKind, file: &str) -> Self {
+        let now = Utc::now();
+        Self {
+            id: ObjectId::new().to_hex(),
+            kind,
+            file: file.to_string(),
+            status: JobStatus::Pending,
+            attempts: 0,
+            error: None,
+            created: now,
+            updated: now,
+            run_after: now,
+        }
+    }
+
+    async fn run(&self, db: &DBWrapper) -> Result<(), String> {
+        match self.kind {
+            JobKind::Transcode => self.transcode(db).await,
+            JobKind::Hls => self.hls(db).await,
+            JobKind::Sprites => self.sprites(db).await,
+            JobKind::Probe => self.probe(db).await,
+            JobKind::Subtitles => self.subtitles(db).await,
+        }
+    }
+
+    async fn subtitles(&self, db: &DBWrapper) -> Result<(), String> {
+        let source = db.get_video_file(&self.file)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or("source file not found")?;
+        let count = super::subtitle::extract(&source, db).await?;
+        log::info!("extracted {} subtitle tracks from file {}", count, source.id);
+        Ok(())
+    }
+
+    async fn probe(&self, db: &DBWrapper) -> Result<(), String> {
+        let source = db.get_video_file(&self.file)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or("source file not found")?;
+        let streams = source.probe_streams().await.map_err(|e| e.message())?;
+        db.set_streams(&source.id, streams).await.map_err(|e| e.to_string())
+    }
+
+    async fn sprites(&self, db: &DBWrapper) -> Result<(), String> {
+        let source = db.get_video_file(&self.file)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or("source file not found")?;
+        let sheet = super::sprite::generate(&source).await?;
+        db.set_sprite(&source.id, sheet).await.map_err(|e| e.to_string())
+    }
+
+    async fn hls(&self, db: &DBWrapper) -> Result<(), String> {
+        let source = db.get_video_file(&self.file)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or("source file not found")?;
+        let renditions = super::hls::generate(&source).await?;
+        log::info!("generated {} hls renditions for file {}", renditions.len(), source.id);
+        db.set_hls(&source.id, renditions).await.map_err(|e| e.to_string())
+    }
+
+    async fn transcode(&self, db: &DBWrapper) -> Result<(), String> {
+        let source = db.get_video_file(&self.file)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or("source file not found")?;
+        if source.converted.is_some() {
+            log::info!("file {} is already converted, skipping job {}", source.id, self.id);
+            return Ok(())
+        }
+
+        let target = Path::new(&CONFIG.video_storage).join("transcode").join(format!("{}.mp4", self.id));
+        let local = source.local_file().await.map_err(|e| e.to_string())?;
+        let mut cmd = Tool::Ffmpeg.command();
+        cmd
+            .args(["-y", "-v", "error", "-i"])
+            .arg(local.path())
+            .args(["-map", "0:v:0?", "-map", "0:a:0?"]);
+        // avoid re-encoding streams that are already fine
+        match source.h264_playable() {
+            true => cmd.args(["-c:v", "copy"]),
+            false => cmd.args(["-c:v", "libx264", "-preset", "veryfast", "-crf", "23", "-pix_fmt", "yuv420p"]),
+        };
+        match source.audio_codec {
+            super::file::AudioCodec::Aac => cmd.args(["-c:a", "copy"]),
+            _ => cmd.args(["-c:a", "aac", "-b:a", "160k"]),
+        };
+        cmd
+            .args(["-movflags", "+faststart"])
+            .arg(&target);
+
+        let res = cmd.run().await;
+        drop(local);
+        if let Err(e) = res {
+            let _ = std::fs::remove_file(&target);
+            return Err(e.to_string());
+        }
+
+        // the thumbnail of the source is kept, the converted file doesn't need one
+        let converted = match VideoFile::probe_path(&target).await {
+            Ok(f) => f,
+            Err(e) => {
+                let _ = std::fs::remove_file(&target);
+                return Err(e.message());
+            }
+        };
+        let cid = converted.id.clone();
+        if let Err(e) = STORAGE.put(converted.key(), &target).await {
+            let _ = std::fs::remove_file(&target);
+            return Err(format!("moving converted file: {}", e));
+        }
+        if let Err(e) = db.insert_video_file(converted).await {
+            let _ = STORAGE.delete(&cid).await;
+            return Err(e.to_string());
+        }
+        db.set_converted(&source.id, &cid).await.map_err(|e| e.to_string())?;
+        log::info!("file {} converted to {}", source.id, cid);
+        Ok(())
+    }
+}
+
+impl DBWrapper {
+    pub(crate) async fn enqueue_job(&self, kind: JobKind, file: &str) -> Result<Job, mongodb::error::Error> {
+        let job = Job::new(kind, file);
+        self
+            .collection::<Job>(Self::JOBS)
+            .insert_one(&job, None)
+            .await?;
+        Ok(job)
+    }
+
+    // atomically takes the oldest pending job whose delay has expired
+    async fn claim_job(&self) -> Result<Option<Job>, mongodb::error::Error> {
+        let now = mongodb::bson::DateTime::from_chrono(Utc::now());
+        self
+            .collection::<Job>(Self::JOBS)
+            .find_one_and_update(
+                doc! {"status": JobStatus::Pending.as_str(), "run_after": {"$lte": now}},
+                doc! {"$set": {"status": JobStatus::Running.as_str(), "updated": now}, "$inc": {"attempts": 1}},
+                FindOneAndUpdateOptions::builder()
+                    .sort(doc! {"created": 1})
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await
+    }
+
+    // jobs left running by a previous process can't be running anymore
+    async fn requeue_running_jobs(&self) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<Job>(Self::JOBS)
+            .update_many(
+                doc! {"status": JobStatus::Running.as_str()},
+                doc! {"$set": {"status": JobStatus::Pending.as_str(), "updated": mongodb::bson::DateTime::now()}},
+                None,
+            )
+            .await
+            .map(|_| ())
+    }
+
+    async fn finish_job(&self, job: &Job, result: Result<(), String>) -> Result<(), mongodb::error::Error> {
+        let now = Utc::now();
+        let update = match result {
+            Ok(()) => doc! {"$set": {"status": JobStatus::Done.as_str(), "error": null, "updated": mongodb::bson::DateTime::from_chrono(now)}},
+            Err(e) => {
+                log::error!("job {} ({:?} on {}) failed: {}", job.id, job.kind, job.file, e);
+                let status = if job.attempts >= CONFIG.jobs.max_attempts {
+                    JobStatus::Failed
+                } else {
+                    JobStatus::Pending
+                };
+                doc! {"$set": {
+                    "status": status.as_str(),
+                    "error": e,
+                    "updated": mongodb::bson::DateTime::from_chrono(now),
+                    "run_after": mongodb::bson::DateTime::from_chrono(now + CONFIG.jobs.retry_delay),
+                }}
+            }
+        };
+        self
+            .collection::<Job>(Self::JOBS)
+            .update_one(doc! {"_id": &job.id}, update, None)
+            .await
+            .map(|_| ())
+    }
+
+    pub(crate) async fn get_jobs(&self, status: Option<JobStatus>) -> Result<Vec<Job>, mongodb::error::Error> {
+        let filter = match status {
+            Some(s) => doc! {"status": s.as_str()},
+            None => doc! {},
+        };
+        self
+            .collection::<Job>(Self::JOBS)
+            .find(filter, FindOptions::builder().sort(doc! {"created": -1}).build())
+            .await?
+            .try_collect()
+            .await
+    }
+
+    pub(crate) async fn retry_job(&self, id: &str) -> Result<Option<Job>, mongodb::error::Error> {
+        let now = mongodb::bson::DateTime::now();
+        self
+            .collection::<Job>(Self::JOBS)
+            .find_one_and_update(
+                doc! {"_id": id, "status": {"$ne": JobStatus::Running.as_str()}},
+                doc! {"$set": {"status": JobStatus::Pending.as_str(), "attempts": 0, "error": null, "updated": now, "run_after": now}},
+                FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build(),
+            )
+            .await
+    }
+
+    pub(super) async fn delete_file_jobs(&self, file: &str) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<Job>(Self::JOBS)
+            .delete_many(doc! {"file": file}, None)
+            .await
+            .map(|_| ())
+    }
+}
+
+async fn worker(db: DBWrapper) {
+    if let Err(e) = db.requeue_running_jobs().await {
+        log::error!("failed to requeue interrupted jobs: {}", e);
+    }
+    let poll = CONFIG.jobs.poll_interval.to_std().unwrap_or_default();
+    loop {
+        match db.claim_job().await {
+            Ok(Some(job)) => {
+                log::info!("running job {} ({:?} on {}), attempt {}", job.id, job.kind, job.file, job.attempts);
+                let result = job.run(&db).await;
+                if let Err(e) = db.finish_job(&job, result).await {
+                    log::error!("failed to update job {}: {}", job.id, e);
+                }
+            }
+            Ok(None) => rocket::tokio::time::sleep(poll).await,
+            Err(e) => {
+                log::error!("failed to fetch next job: {}", e);
+                rocket::tokio::time::sleep(poll).await;
+            }
+        }
+    }
+}
+
+pub(crate) fn spawn_worker(rocket: &Rocket<Orbit>) {
+    if !CONFIG.jobs.enabled {
+        log::warn!("background jobs are disabled");
+        return
+    }
+    match DBWrapper::fetch(rocket) {
+        Some(db) => {
+            rocket::tokio::spawn(worker(db));
+        }
+        None => log::error!("Failed to fetch database connection for job worker"),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(transparent)]
+pub(crate) struct ListResponse {
+    inner: Vec<Job>,
+}
+
+impl ApiResponse for ListResponse {}
+
+impl ApiResponse for Job {}
+
+#[derive(Serialize)]
+pub(crate) struct ReprobeResponse {
+    queued: usize,
+}
+
+impl ApiResponse for ReprobeResponse {}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum JobError {
+    JobNotFound,
+}
+
+impl ApiErrorType for JobError {
+    fn ty(&self) -> &'static str {
+        match self {
+            Self::JobNotFound => "job_not_found",
+        }
+    }
+
+    fn status(&self) -> rocket::http::Status {
+        match self {
+            Self::JobNotFound => rocket::http::Status::NotFound,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::JobNotFound => "Job not found or currently running".to_string(),
+        }
+    }
+}
+
+#[get("/?<status>")]
+pub(crate) async fn list(status: Option<JobStatus>, user: Result<UserGuard<IsAdmin>, AuthenticationError>, db: DBWrapper) -> ApiResponder<ListResponse> {
+    let _ = user?;
+    ListResponse { inner: db.get_jobs(status).await? }.into()
+}
+
+#[post("/<id>/retry")]
+pub(crate) async fn retry(id: &str, user: Result<UserGuard<IsAdmin>, AuthenticationError>, db: DBWrapper) -> ApiResponder<Job> {
+    let _ = user?;
+    match db.retry_job(id).await? {
+        Some(job) => job.into(),
+        None => ApiResponder::Err(JobError::JobNotFound.into()),
+    }
+}
+
+// queues a probe job for every file missing stream metadata, or for all files with `all=true`
+#[post("/reprobe?<all>")]
+pub(crate) async fn reprobe(all: Option<bool>, user: Result<UserGuard<IsAdmin>, AuthenticationError>, db: DBWrapper) -> ApiResponder<ReprobeResponse> {
+    let _ = user?;
+    let files = db.get_files_to_probe(all.unwrap_or(false)).await?;
+    for file in &files {
+        db.enqueue_job(JobKind::Probe, file).await?;
+    }
+    ReprobeResponse { queued: files.len() }.into()
+}
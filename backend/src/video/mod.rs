@@ -1,25 +1,43 @@
 mod token;
+pub(crate) mod analytics;
 mod file;
 pub mod share;
-
+pub mod job;
+pub(crate) mod hls;
+pub(crate) mod sprite;
+pub(crate) mod custom_thumb;
+pub(crate) mod subtitle;
+pub(crate) mod clip;
+pub(crate) mod query;
+pub(crate) mod expand;
+pub(crate) mod embed;
+pub mod tus;
+
+use std::collections::HashSet;
 use std::path::Path;
 
 use chrono::{DateTime, Utc};
 use file::VideoFile;
+use job::JobKind;
 use rand::Rng;
-use rocket::fs::NamedFile;
 use rocket::futures::{TryStreamExt, StreamExt};
 use rocket::serde::json::Json;
-use rocket::{form::Form, fs::TempFile};
+use rocket::{form::Form, fs::TempFile, http::ContentType};
 use rocket_db_pools::mongodb;
 use rocket_db_pools::mongodb::bson::Document;
 use rocket_db_pools::mongodb::bson::doc;
 use serde::{Serialize, Deserialize};
+use expand::{Expand, Expanded};
+use query::{QueryStages, VideoQuery};
 use token::VideoToken;
 
+use crate::cursor::{Cursor, NEXT_CURSOR_HEADER};
+use crate::media::Validators;
+use crate::storage::STORAGE;
+use crate::history::Progress;
 use crate::response::ApiError;
 use crate::user::{ExpiringToken, User};
-use crate::{authentication::{AuthenticationError, UserGuard}, config::CONFIG, db::DBWrapper, response::{ApiErrorType, ApiResponder, ApiResponse}, user::Permissions};
+use crate::{authentication::{AuthenticationError, UserGuard}, config::CONFIG, db::{error_code, DBWrapper, DUPLICATE_KEY}, response::{ApiErrorType, ApiResponder, ApiResponse}, user::Permissions};
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
@@ -58,15 +76,62 @@ pub(crate) struct Video {
     pub id: String,
     pub file: Either<String, VideoFile>,
     name: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
     game: String,
     public: bool,
     owner: String,
     added: DateTime<Utc>,
+    // set on videos cut from another one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    clip: Option<clip::ClipSource>,
+    // version of the video's custom thumbnail, 0 if it uses the file's one.
+    //   files can be shared by several videos, so custom thumbnails belong to the video
+    #[serde(default)]
+    thumb_version: u32,
 }
 
+const MAX_DESCRIPTION_LEN: usize = 5000;
+const MAX_TAGS: usize = 32;
+const MAX_TAG_LEN: usize = 64;
+
 static CODE_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-_";
 
 impl Video {
+    pub(crate) fn thumb_key(&self) -> String {
+        format!("thumbs/video_{}.jpg", self.id)
+    }
+
+    // key and version of the thumbnail shown for the video, the file must be resolved
+    pub(crate) fn thumb(&self) -> (String, u32) {
+        match self.thumb_version {
+            0 => {
+                let file = self.file.as_ref().unwrap_right();
+                (VideoFile::thumb_key(&file.id), file.thumb_version)
+            }
+            v => (self.thumb_key(), v),
+        }
+    }
+
+    // a freshly uploaded video, `file` is the stored file or, for duplicate uploads, the one it shares
+    fn uploaded(id: String, file: Either<String, VideoFile>, owner: &str, game: &str, name: Option<String>, public: bool) -> Self {
+        Self {
+            id,
+            file,
+            name,
+            description: None,
+            tags: vec![],
+            game: game.to_string(),
+            public,
+            owner: owner.to_string(),
+            added: Utc::now(),
+            clip: None,
+            thumb_version: 0,
+        }
+    }
+
     fn random_code() -> String {
         let mut rng = rand::thread_rng();
         // get 6 random chars from CODE_CHARS
@@ -85,8 +150,8 @@ impl Video {
         }
     }
 
-    pub(crate) fn generate_token(&self) -> VideoToken {
-        VideoToken::new(&self.id)
+    pub(crate) fn generate_token(&self, user: Option<&User>) -> VideoToken {
+        VideoToken::new(&self.id, user)
     }
 
     pub(crate) async fn user_authorized(&self, user: Option<&User>, db: &DBWrapper) -> Result<bool, mongodb::error::Error> {
@@ -98,6 +163,24 @@ impl Video {
         )
     }
 
+    // same as `user_authorized`, with the games of the user already known
+    pub(crate) fn user_authorized_in(&self, user: Option<&User>, user_games: &HashSet<String>) -> bool {
+        self.public ||
+            match user {
+                Some(user) => user.allowed(Permissions::WATCH_VIDEO) || user_games.contains(&self.game),
+                None => false,
+            }
+    }
+
+    pub(crate) fn is_owner(&self, user: &User) -> bool {
+        self.owner == user.username
+    }
+
+    // owners can always modify their videos, others need the permission and to be part of the video's game
+    pub(crate) fn user_can_modify(&self, user: &User, user_games: &HashSet<String>) -> bool {
+        self.owner == user.username || (user.allowed(Permissions::MODIFY_VIDEO_OTHERS) && user_games.contains(&self.game))
+    }
+
     // fails if file is Either::Left
     pub(crate) async fn resolve_converted(&mut self, db: &DBWrapper) -> Result<(), mongodb::error::Error> {
         if let Some(conv) = self.file.as_ref().unwrap_right().converted.clone() {
@@ -116,6 +199,71 @@ impl DBWrapper {
             .is_none())
     }
 
+    pub(super) async fn set_converted(&self, file: &str, converted: &str) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<VideoFile>(Self::VIDEO_FILES)
+            .update_one(doc! { "_id": file }, doc! { "$set": { "converted": converted } }, None)
+            .await?;
+        Ok(())
+    }
+
+    pub(super) async fn set_hls(&self, file: &str, renditions: Vec<hls::Rendition>) -> Result<(), mongodb::error::Error> {
+        let renditions = mongodb::bson::to_bson(&renditions)?;
+        self
+            .collection::<VideoFile>(Self::VIDEO_FILES)
+            .update_one(doc! { "_id": file }, doc! { "$set": { "hls": renditions } }, None)
+            .await?;
+        Ok(())
+    }
+
+    pub(super) async fn set_sprite(&self, file: &str, sheet: sprite::SpriteSheet) -> Result<(), mongodb::error::Error> {
+        let sheet = mongodb::bson::to_bson(&sheet)?;
+        self
+            .collection::<VideoFile>(Self::VIDEO_FILES)
+            .update_one(doc! { "_id": file }, doc! { "$set": { "sprite": sheet } }, None)
+            .await?;
+        Ok(())
+    }
+
+    pub(super) async fn set_streams(&self, file: &str, streams: Vec<file::StreamInfo>) -> Result<(), mongodb::error::Error> {
+        let streams = mongodb::bson::to_bson(&streams)?;
+        self
+            .collection::<VideoFile>(Self::VIDEO_FILES)
+            .update_one(doc! { "_id": file }, doc! { "$set": { "streams": streams } }, None)
+            .await?;
+        Ok(())
+    }
+
+    // ids of the files that have no stream metadata, or of every file if `all` is set
+    pub(super) async fn get_files_to_probe(&self, all: bool) -> Result<Vec<String>, mongodb::error::Error> {
+        let filter = if all {
+            doc! {}
+        } else {
+            doc! { "$or": [{ "streams": { "$exists": false } }, { "streams": { "$size": 0 } }] }
+        };
+        self
+            .collection::<mongodb::bson::Document>(Self::VIDEO_FILES)
+            .find(filter, mongodb::options::FindOptions::builder().projection(doc! { "_id": 1 }).build())
+            .await?
+            .try_filter_map(|d| async move { Ok(d.get_str("_id").ok().map(|s| s.to_string())) })
+            .try_collect()
+            .await
+    }
+
+    // the first custom thumbnail of a video starts after the file's version, so that urls never repeat
+    pub(super) async fn bump_thumb_version(&self, video: &Video) -> Result<u32, mongodb::error::Error> {
+        let file_version = video.file.as_ref().unwrap_right().thumb_version;
+        let updated = self
+            .collection::<Video>(Self::VIDEOS)
+            .find_one_and_update(
+                doc! { "_id": &video.id },
+                vec![doc! { "$set": { "thumb_version": { "$add": [{ "$max": [{ "$ifNull": ["$thumb_version", 0] }, file_version] }, 1] } } }],
+                mongodb::options::FindOneAndUpdateOptions::builder().return_document(mongodb::options::ReturnDocument::After).build(),
+            )
+            .await?;
+        Ok(updated.map(|v| v.thumb_version).unwrap_or_default())
+    }
+
     pub(super) async fn insert_video_file(&self, video: VideoFile) -> Result<(), mongodb::error::Error> {
         self
             .collection::<VideoFile>(Self::VIDEO_FILES)
@@ -147,18 +295,76 @@ impl DBWrapper {
             .await
     }
 
-    pub(crate) async fn get_user_videos(&self, user: &User, sort: bool, skip: Option<u32>, limit: Option<u32>) -> Result<(usize, Vec<Video>), mongodb::error::Error> {
-        let mut pipeline = vec![];
-        let m = if user.allowed(Permissions::ADMIN) {
+    // matches the videos a user can see: own, public or from one of their games
+    pub(crate) async fn visible_videos_filter(&self, user: &User) -> Result<Document, mongodb::error::Error> {
+        Ok(if user.allowed(Permissions::ADMIN) {
             doc! { }
         } else {
             let user_games = self.get_user_games_ids(user).await?;
             doc! { "$or": [{ "owner": &user.username }, { "public": true }, { "game": { "$in": user_games.into_iter().collect::<Vec<_>>() } }]}
-        };
-        pipeline.push(doc! {"$match": m.clone()});
-        if sort {
-            pipeline.push(doc! {"$sort": {"added": -1}});
+        })
+    }
+
+    // without a query videos are returned unsorted.
+    //   with a limit, the cursor to the following page is returned if there is one.
+    pub(crate) async fn get_user_videos(&self, user: &User, query: Option<QueryStages>, skip: Option<u32>, limit: Option<u32>, cursor: Option<Cursor>) -> Result<(usize, Vec<Video>, Option<Cursor>), mongodb::error::Error> {
+        let mut pipeline = vec![doc! {"$match": self.visible_videos_filter(user).await?}];
+        if let Some(ref q) = query {
+            pipeline.extend(q.filter.iter().cloned());
+        }
+        let mut count_pipeline = pipeline.clone();
+        count_pipeline.push(doc! {"$count": "n"});
+        if let Some(ref q) = query {
+            pipeline.extend(q.key.iter().cloned());
+            if let Some(ref c) = cursor {
+                pipeline.push(doc! {"$match": c.filter(query::KEY, q.order)});
+            }
+            pipeline.push(q.sort());
+        }
+        if let Some(skip) = skip {
+            pipeline.push(doc!{"$skip": skip as i64});
+        }
+        if let Some(limit) = limit {
+            // one more to know whether there is a next page
+            pipeline.push(doc!{"$limit": limit as i64 + 1});
+        }
+        pipeline.push(QueryStages::cleanup());
+        let count = self
+            .collection::<Document>(Self::VIDEOS)
+            .aggregate(count_pipeline, None)
+            .await?
+            .try_next()
+            .await?
+            .and_then(|d| d.get("n").and_then(|n| n.as_i32().map(|n| n as i64).or(n.as_i64())))
+            .unwrap_or(0);
+
+        let mut docs: Vec<Document> = self
+            .collection::<Document>(Self::VIDEOS)
+            .aggregate(pipeline, None)
+            .await?
+            .try_collect()
+            .await?;
+        let mut next = None;
+        if let Some(limit) = limit {
+            if docs.len() > limit as usize {
+                docs.truncate(limit as usize);
+                next = query.as_ref().and_then(|q| docs.last().and_then(|d| Cursor::after(&q.name, d, query::KEY)));
+            }
         }
+        let videos = docs
+            .into_iter()
+            .map(|d| mongodb::bson::from_document::<Video>(d).unwrap())
+            .collect();
+        Ok((count as usize, videos, next))
+    }
+
+    // full text search on name, description and tags, best matches first
+    pub(crate) async fn search_videos(&self, user: &User, query: &str, skip: Option<u32>, limit: Option<u32>) -> Result<(usize, Vec<Video>), mongodb::error::Error> {
+        let m = doc! { "$and": [{ "$text": { "$search": query } }, self.visible_videos_filter(user).await?] };
+        let mut pipeline = vec![
+            doc! {"$match": m.clone()},
+            doc! {"$sort": {"score": {"$meta": "textScore"}, "added": -1}},
+        ];
         if let Some(skip) = skip {
             pipeline.push(doc!{"$skip": skip as i64});
         }
@@ -229,19 +435,62 @@ impl DBWrapper {
         Ok(res.into_iter().next())
     }
 
-    pub(super) async fn delete_video(&self, video: &Video) -> Result<(), mongodb::error::Error> {
+    // returns whether the referenced file isn't used by any other video anymore and has been removed from db
+    pub(super) async fn delete_video(&self, video: &Video) -> Result<bool, mongodb::error::Error> {
         self
             .collection::<Video>(Self::VIDEOS)
             .delete_one(doc! { "_id": &video.id }, None)
             .await?;
-        // delete referenced video file
-        self.delete_video_file(&video.file.as_ref().unwrap_right().id).await?;
         // delete referenced likes
         self
             .collection::<()>("likes")
             .delete_many(doc! { "video": &video.id }, None)
             .await?;
-        Ok(())
+        // delete referenced comments
+        self.delete_video_comments(&video.id).await?;
+        self.delete_video_history(&video.id).await?;
+        self.delete_video_views(&video.id).await?;
+        self.delete_video_share_links(&video.id).await?;
+        self.delete_video_subtitles(&video.id).await?;
+        self.remove_video_from_playlists(&video.id).await?;
+        // the file could be shared with other videos having the same content
+        let file = video.file.as_ref().unwrap_right();
+        let shared = self
+            .collection::<Video>(Self::VIDEOS)
+            .count_documents(doc! { "file": &file.id }, None)
+            .await? > 0;
+        if shared {
+            return Ok(false);
+        }
+        // delete referenced video file, its converted version and pending jobs
+        if let Some(ref conv) = file.converted {
+            if let Some(conv) = self.get_video_file(conv).await? {
+                if let Err(e) = conv.delete().await {
+                    error!("error while deleting converted file {}: {}", conv.id, e);
+                }
+                self.delete_video_file(&conv.id).await?;
+            }
+        }
+        self.delete_file_jobs(&file.id).await?;
+        self.delete_embedded_subtitles(&file.id).await?;
+        self.delete_video_file(&file.id).await?;
+        Ok(true)
+    }
+
+    // the stored file with the given content hash, the original upload and not a converted version
+    pub(super) async fn get_video_file_by_hash(&self, hash: &str) -> Result<Option<VideoFile>, mongodb::error::Error> {
+        self
+            .collection::<VideoFile>(Self::VIDEO_FILES)
+            .find_one(doc! { "hash": hash }, None)
+            .await
+    }
+
+    // the video of a game showing the given file, if any
+    async fn get_game_video_by_file(&self, file: &str, game: &str) -> Result<Option<Video>, mongodb::error::Error> {
+        self
+            .collection::<Video>(Self::VIDEOS)
+            .find_one(doc! { "file": file, "game": game }, None)
+            .await
     }
 
     pub(super) async fn delete_video_file(&self, id: &str) -> Result<(), mongodb::error::Error> {
@@ -288,6 +537,8 @@ pub(crate) enum UploadError {
     VideoAlreadyExists(String),
     ProbeError(&'static str),
     FormatError(&'static str),
+    // the quota that would be exceeded, "user" or "game", and its limit in bytes
+    QuotaExceeded(&'static str, u64),
 }
 
 impl ApiErrorType for UploadError {
@@ -297,6 +548,7 @@ impl ApiErrorType for UploadError {
             Self::VideoAlreadyExists(_) => "video_already_exists",
             Self::ProbeError(_) => "probe_error",
             Self::FormatError(_) => "format_error",
+            Self::QuotaExceeded(..) => "quota_exceeded",
         }
     }
 
@@ -306,19 +558,166 @@ impl ApiErrorType for UploadError {
             Self::VideoAlreadyExists(_) => rocket::http::Status::Conflict,
             Self::ProbeError(_) => rocket::http::Status::InternalServerError,
             Self::FormatError(_) => rocket::http::Status::BadRequest,
+            Self::QuotaExceeded(..) => rocket::http::Status::InsufficientStorage,
         }
     }
 
     fn message(&self) -> String {
         match self {
             Self::GameNotFound => "Game not found".to_string(),
-            Self::VideoAlreadyExists(_) => "Video already exists".to_string(),
+            Self::VideoAlreadyExists(id) => format!("Video already exists: {}", id),
             Self::ProbeError(s) => format!("Error while probing video for metadata: {}", s),
             Self::FormatError(s) => format!("Uploaded file has some format errors: {}", s),
+            Self::QuotaExceeded(scope, limit) => format!("Upload would exceed the {} storage quota of {} bytes", scope, limit),
         }
     }
 }
 
+// where the uploaded bytes are before being moved into the storage
+pub(super) enum IngestSource<'a, 'r> {
+    Temp(&'a mut TempFile<'r>),
+    Path(&'a Path),
+}
+
+impl IngestSource<'_, '_> {
+    fn path(&self) -> &Path {
+        match self {
+            Self::Temp(f) => f.path().unwrap(),
+            Self::Path(p) => p,
+        }
+    }
+
+    async fn store(self, key: &str) -> std::io::Result<()> {
+        match self {
+            Self::Temp(f) => {
+                // rocket's temp dir could be anywhere, bring the file next to the others first
+                let staged = Path::new(&CONFIG.video_storage).join("uploads").join(format!("ingest_{}", key));
+                f.move_copy_to(&staged).await?;
+                let res = STORAGE.put(key, &staged).await;
+                if res.is_err() {
+                    let _ = rocket::tokio::fs::remove_file(&staged).await;
+                }
+                res
+            }
+            Self::Path(p) => STORAGE.put(key, p).await,
+        }
+    }
+
+    // temp files are removed by rocket itself
+    async fn discard(self) -> std::io::Result<()> {
+        match self {
+            Self::Temp(_) => Ok(()),
+            Self::Path(p) => rocket::tokio::fs::remove_file(p).await,
+        }
+    }
+}
+
+impl DBWrapper {
+    async fn generate_video_code(&self) -> Result<String, mongodb::error::Error> {
+        // generate random code: https://github.com/topongo/movieStore/blob/master/video_share/models.py#L25
+        let mut code;
+        // check if code isn't clashing
+        loop {
+            code = Video::random_code();
+            if self.check_video_code(&code).await? { break }
+            log::warn!("code clashes: {}", code);
+        }
+        Ok(code)
+    }
+}
+
+// probes and stores an uploaded file, then creates the video pointing to it.
+//   every upload method must end up here.
+pub(super) async fn ingest(db: &DBWrapper, user: &User, game: &str, name: Option<String>, public: bool, source: IngestSource<'_, '_>) -> Result<Video, ApiError> {
+    let hash = file::hash_file(source.path()).await?;
+    if let Some(f) = db.get_video_file_by_hash(&hash).await? {
+        return share(db, user, game, name, public, source, f.id).await;
+    }
+
+    // only files stored anew count against the quotas.
+    //   converted versions are created later, they can take usage past the quota
+    let size = rocket::tokio::fs::metadata(source.path()).await?.len();
+    let reservation = db.reserve_quota(user, game, size).await?;
+    let res = store_new(db, user, game, name, public, source, hash).await;
+    // the video is part of the usage by now, or there is nothing to count
+    db.release_quota(reservation).await;
+    res
+}
+
+// same content is already stored: share the file instead of storing it twice
+async fn share(db: &DBWrapper, user: &User, game: &str, name: Option<String>, public: bool, source: IngestSource<'_, '_>, fid: String) -> Result<Video, ApiError> {
+    if let Some(v) = db.get_game_video_by_file(&fid, game).await? {
+        return Err(UploadError::VideoAlreadyExists(v.id).into());
+    }
+    if STORAGE.exists(&fid).await? {
+        if let Err(e) = source.discard().await {
+            log::warn!("failed to discard duplicate upload: {}", e);
+        }
+    } else {
+        // lost by the storage, or still being stored by a concurrent upload: the content is the same either way
+        source.store(&fid).await?;
+    }
+    let video = Video::uploaded(db.generate_video_code().await?, Either::Left(fid), &user.username, game, name, public);
+    db.insert_video(&video).await?;
+    Ok(video)
+}
+
+async fn store_new(db: &DBWrapper, user: &User, game: &str, name: Option<String>, public: bool, source: IngestSource<'_, '_>, hash: String) -> Result<Video, ApiError> {
+    // get video metadata
+    let mut vfile = VideoFile::from_path(source.path()).await?;
+    vfile.hash = Some(hash.clone());
+    let convert = !vfile.browser_friendly();
+    let has_video = vfile.has_video();
+    let has_subtitles = vfile.has_text_subtitles();
+
+    let code = db.generate_video_code().await?;
+    // insert video file in db
+    let fid = vfile.id.clone();
+    match db.insert_video_file(vfile).await {
+        Ok(()) => {}
+        // a concurrent upload of the same content got its file in first
+        Err(e) if error_code(&e) == Some(DUPLICATE_KEY) => {
+            if let Err(e) = STORAGE.delete(&VideoFile::thumb_key(&fid)).await {
+                log::warn!("failed to remove thumbnail of duplicate upload: {}", e);
+            }
+            return match db.get_video_file_by_hash(&hash).await? {
+                Some(f) => share(db, user, game, name, public, source, f.id).await,
+                None => Err(e.into()),
+            };
+        }
+        Err(e) => return Err(e.into()),
+    }
+    // insert video in db
+    let video = Video::uploaded(code, Either::Left(fid.clone()), &user.username, game, name, public);
+
+    // delete video file if video insertion fails
+    if let Err(e) = db.insert_video(&video).await {
+        db.delete_video_file(&fid).await?;
+        return Err(e.into());
+    }
+    // move file to storage only if everything is successful
+    // if moving fails then remove video and file from db
+    if let Err(e) = source.store(&fid).await {
+        db.collection::<Video>(DBWrapper::VIDEOS).delete_one(doc! { "_id": &video.id }, None).await?;
+        db.delete_video_file(&fid).await?;
+        return Err(e.into());
+    }
+    // browsers can't play this file, convert it in background
+    if convert {
+        db.enqueue_job(JobKind::Transcode, &fid).await?;
+    }
+    if CONFIG.hls.enabled && has_video {
+        db.enqueue_job(JobKind::Hls, &fid).await?;
+    }
+    if CONFIG.sprites.enabled && has_video {
+        db.enqueue_job(JobKind::Sprites, &fid).await?;
+    }
+    if has_subtitles {
+        db.enqueue_job(JobKind::Subtitles, &fid).await?;
+    }
+    Ok(video)
+}
+
 #[post("/upload", data = "<form>")]
 pub(crate) async fn upload(mut form: Form<UploadForm<'_>>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<UploadResponse> {
     let user = user?.user;
@@ -334,92 +733,139 @@ pub(crate) async fn upload(mut form: Form<UploadForm<'_>>, user: Result<UserGuar
     }
     let game = form.game.clone();
 
-    // get video metadata
     let mut videos = vec![];
     for file in form.files.iter_mut() {
-        let vfile = VideoFile::from_path(file.file.path().unwrap()).await?;
-
-        // generate random code: https://github.com/topongo/movieStore/blob/master/video_share/models.py#L25
-        let mut code;
-        // check if code isn't clashing
-        loop {
-            code = Video::random_code();
-            if db.check_video_code(&code).await? { break }
-            log::warn!("code clashes: {}", code);
-        }
-        // insert video file in db
-        let fid = vfile.id.clone();
-        db.insert_video_file(vfile).await?;
-        // insert video in db
-        let video = Video {
-            id: code,
-            file: Either::Left(fid.clone()),
-            name: file.name.clone(),
-            game: game.clone(),
-            public: file.public,
-            owner: user.username.clone(),
-            added: Utc::now(),
-        };
-
-        // delete video file if video insertion fails
-        if let Err(e) = db.insert_video(&video).await {
-            db.delete_video_file(&fid).await?;
-            return ApiResponder::Err(e.into());
+        let video = ingest(&db, &user, &game, file.name.clone(), file.public, IngestSource::Temp(&mut file.file)).await;
+        match video {
+            Ok(v) => videos.push(v),
+            Err(e) => return ApiResponder::Err(e),
         }
-        // move file to storage only if everything is successful
-        let target = Path::new(&CONFIG.video_storage).join(&fid);
-        // if moving fails then remove video and file from db
-        if let Err(e) = file.file.move_copy_to(target).await {
-            db.delete_video(&video).await?;
-            db.delete_video_file(&fid).await?;
-            return ApiResponder::Err(e.into());
-        }
-        videos.push(video);
     }
     UploadResponse { inner: videos }.into()
 }
 
+// a video as shown in listings, along with the requesting user's watch progress and the expanded fields
+#[derive(Serialize)]
+pub(crate) struct ListedVideo {
+    #[serde(flatten)]
+    video: Video,
+    progress: Option<Progress>,
+    #[serde(flatten)]
+    expanded: Expanded,
+}
+
+impl ListedVideo {
+    pub(crate) fn new(video: Video, progress: Option<Progress>) -> Self {
+        Self { video, progress, expanded: Expanded::default() }
+    }
+
+    pub(crate) async fn with_progress(videos: Vec<Video>, user: &User, db: &DBWrapper) -> Result<Vec<Self>, mongodb::error::Error> {
+        Self::load(videos, user, Expand::default(), db).await
+    }
+
+    pub(crate) async fn load(mut videos: Vec<Video>, user: &User, expand: Expand, db: &DBWrapper) -> Result<Vec<Self>, mongodb::error::Error> {
+        let ids = videos.iter().map(|v| v.id.clone()).collect::<Vec<_>>();
+        let mut progress = db.get_videos_progress(user, &ids).await?;
+        let expanded = db.expand_videos(&mut videos, expand, user).await?;
+        Ok(videos
+            .into_iter()
+            .zip(expanded)
+            .map(|(v, expanded)| {
+                let progress = progress.remove(&v.id);
+                Self { video: v, progress, expanded }
+            })
+            .collect())
+    }
+}
+
 #[derive(Serialize)]
 #[serde(transparent)]
 pub(crate) struct GetResponse {
-    inner: Vec<Video>,
+    inner: Vec<ListedVideo>,
 }
 
 impl ApiResponse for GetResponse {}
 
-#[get("/?<limit>&<skip>")]
+#[get("/?<limit>&<skip>&<cursor>&<expand>&<query..>")]
 pub(crate) async fn list(
     user: Result<UserGuard<()>, AuthenticationError>, 
     db: DBWrapper,
     limit: Option<u32>,
     skip: Option<u32>,
+    cursor: Option<&str>,
+    expand: Option<&str>,
+    query: VideoQuery,
+    ) -> ApiResponder<GetResponse> {
+    let user = user?.user;
+    if !user.allowed(Permissions::VIEW_VIDEOS) {
+        return AuthenticationError::InsufficientPermissions(Permissions::VIEW_VIDEOS).into();
+    }
+    let expand = Expand::parse(expand)?;
+
+    let limit = match limit {
+        Some(l) => l.min(50),
+        None => 50,
+    };
+
+    let stages = match query.stages() {
+        Ok(s) => s,
+        Err(e) => return ApiResponder::Err(e.into()),
+    };
+    let cursor = match cursor.map(|c| Cursor::decode(c, &stages.name)).transpose() {
+        Ok(c) => c,
+        Err(e) => return ApiResponder::Err(e.into()),
+    };
+    let (count, videos, next) = db.get_user_videos(&user, Some(stages), skip, Some(limit), cursor).await?;
+
+    let mut headers = vec![("X-Total-Count", count.to_string())];
+    if let Some(next) = next {
+        headers.push((NEXT_CURSOR_HEADER, next.encode()));
+    }
+    let videos = ListedVideo::load(videos, &user, expand, &db).await?;
+    ApiResponder::OkWithHeaders(GetResponse { inner: videos }, headers)
+}
+
+#[get("/search?<q>&<limit>&<skip>&<expand>")]
+pub(crate) async fn search(
+    q: &str,
+    user: Result<UserGuard<()>, AuthenticationError>,
+    db: DBWrapper,
+    limit: Option<u32>,
+    skip: Option<u32>,
+    expand: Option<&str>,
     ) -> ApiResponder<GetResponse> {
     let user = user?.user;
     if !user.allowed(Permissions::VIEW_VIDEOS) {
         return AuthenticationError::InsufficientPermissions(Permissions::VIEW_VIDEOS).into();
     }
+    let expand = Expand::parse(expand)?;
 
     let limit = match limit {
         Some(l) => l.min(50),
         None => 50,
     };
 
-    let (count, videos) = db.get_user_videos(&user, true, skip, Some(limit)).await?;
+    let (count, videos) = db.search_videos(&user, q, skip, Some(limit)).await?;
 
+    let videos = ListedVideo::load(videos, &user, expand, &db).await?;
     ApiResponder::OkWithHeaders(GetResponse { inner: videos }, vec![("X-Total-Count", count.to_string())])
 }
 
 impl ApiResponse for Video {}
 
-#[get("/<id>")]
-pub(crate) async fn get(id: &str, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<Video> {
+impl ApiResponse for ListedVideo {}
+
+#[get("/<id>?<expand>")]
+pub(crate) async fn get(id: &str, expand: Option<&str>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<ListedVideo> {
     let user = user?.user;
+    let expand = Expand::parse(expand)?;
     let video = match db.get_video(id).await? {
         Some(v) => v,
         None => return ApiResponder::Err(ApiError::not_found()),
     };
     if video.user_authorized(Some(&user), &db).await? {
-        video.into()
+        let mut videos = ListedVideo::load(vec![video], &user, expand, &db).await?;
+        videos.remove(0).into()
     } else {
         AuthenticationError::InsufficientPermissions(Permissions::WATCH_VIDEO).into()
     }
@@ -443,14 +889,37 @@ pub(crate) async fn list_file(user: Result<UserGuard<()>, AuthenticationError>,
     GetFileResponse { inner: db.get_video_files(vec![]).await? }.into()
 }
 
-pub(crate) struct ThumbResponder(Option<NamedFile>);
+pub(crate) enum ThumbResponder {
+    // `versioned` is set when the url carries the current thumb_version, which changes with the thumbnail
+    Found { content: Vec<u8>, validators: Validators, versioned: bool },
+    Err(ApiError),
+}
 
 impl<'r, 'o: 'r> rocket::response::Responder<'r, 'o> for ThumbResponder {
     fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
-        match self.0 {
-            Some(file) => file.respond_to(request),
-            None => ApiResponder::<()>::Err(ApiError::not_found()).respond_to(request),
+        match self {
+            Self::Found { content, validators, versioned } => {
+                let cache = if versioned { "public, max-age=31536000, immutable" } else { "no-cache" };
+                if validators.not_modified(request.headers()) {
+                    return validators.not_modified_response(cache);
+                }
+                let mut res = rocket::Response::build_from((ContentType::JPEG, content).respond_to(request)?);
+                res.raw_header("Cache-Control", cache);
+                validators.apply(&mut res);
+                res.ok()
+            }
+            Self::Err(e) => ApiResponder::<()>::Err(e).respond_to(request),
+        }
+    }
+}
+
+impl DBWrapper {
+    // storage key and version of the thumbnail shown for a video, or of a file's own one
+    async fn thumb_of(&self, id: &str) -> Result<Option<(String, u32)>, mongodb::error::Error> {
+        if let Some(video) = self.get_video_resolved(id).await? {
+            return Ok(Some(video.thumb()));
         }
+        Ok(self.get_video_file(id).await?.map(|f| (VideoFile::thumb_key(&f.id), f.thumb_version)))
     }
 }
 
@@ -458,11 +927,25 @@ impl<'r, 'o: 'r> rocket::response::Responder<'r, 'o> for ThumbResponder {
 //  - not that simple: flutter's image caching wont work with auth.
 //  - we may use the video token to get the thumb
 //  - what the hell, we can just keep this public.
-#[get("/<id>/thumb")]
-pub(crate) async fn thumb(id: &str) -> ThumbResponder {
-    match VideoFile::thumb(id) {
-        Some(f) => ThumbResponder(Some(NamedFile::open(f).await.unwrap())),
-        None => ThumbResponder(None),
+// `id` is a video id, or a file id for the file's own thumbnail
+#[get("/<id>/thumb?<v>")]
+pub(crate) async fn thumb(id: &str, v: Option<u32>, db: DBWrapper) -> ThumbResponder {
+    let (key, version) = match db.thumb_of(id).await {
+        Ok(Some(t)) => t,
+        Ok(None) => return ThumbResponder::Err(ApiError::not_found()),
+        Err(e) => return ThumbResponder::Err(e.into()),
+    };
+    match STORAGE.get(&key).await {
+        Ok(Some((content, info))) => ThumbResponder::Found {
+            content,
+            validators: Validators::new(&key, &info),
+            versioned: v == Some(version),
+        },
+        Ok(None) => ThumbResponder::Err(ApiError::not_found()),
+        Err(e) => {
+            error!("error while reading thumbnail {}: {}", key, e);
+            ThumbResponder::Err(e.into())
+        }
     }
 }
 
@@ -508,7 +991,7 @@ pub(crate) async fn get_token(video: &str, user: Result<UserGuard<()>, Authentic
     };
 
     if video.user_authorized(user.as_ref().ok().map(|u| &u.user), &db).await? {
-        let token = video.generate_token();
+        let token = video.generate_token(user.as_ref().ok().map(|u| &u.user));
         db.add_video_token(&token).await?;
         TokenResponse { inner: token.token }.into()
     } else {
@@ -567,8 +1050,13 @@ pub(crate) async fn delete(video: &str, user: Result<UserGuard<()>, Authenticati
     if video.owner != user.username && !user.allowed(Permissions::ADMIN) {
         AuthenticationError::InsufficientPermissions(Permissions::ADMIN).into()
     } else {
-        db.delete_video(&video).await?;
-        if video.file.as_ref().unwrap_right().delete().is_err() {
+        let orphaned = db.delete_video(&video).await?;
+        if video.thumb_version > 0 {
+            if let Err(e) = STORAGE.delete(&video.thumb_key()).await {
+                error!("error while deleting thumbnail of video {}: {}", video.id, e);
+            }
+        }
+        if orphaned && video.file.as_ref().unwrap_right().delete().await.is_err() {
             error!("error while deleting video file {}. this could be a phantom db entry.", video.file.unwrap_right().id);
             if cfg!(debug_assertions) {
                 DeleteResponse { inner: video.id }.into()
@@ -586,11 +1074,38 @@ pub(crate) struct UpdateForm {
     name: Option<String>,
     public: Option<bool>,
     game: Option<String>,
+    // an empty description removes it
+    description: Option<String>,
+    tags: Option<Vec<String>>,
+}
+
+// tags are compared case insensitively, so they are stored lowercase and without duplicates
+fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut out: Vec<String> = vec![];
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if !tag.is_empty() && !out.contains(&tag) {
+            out.push(tag);
+        }
+    }
+    out
 }
 
 impl UpdateForm {
+    fn validate(&self) -> Result<(), UpdateError> {
+        if self.description.as_ref().is_some_and(|d| d.chars().count() > MAX_DESCRIPTION_LEN) {
+            return Err(UpdateError::InvalidDescription);
+        }
+        if let Some(ref tags) = self.tags {
+            if tags.len() > MAX_TAGS || tags.iter().any(|t| t.chars().count() > MAX_TAG_LEN) {
+                return Err(UpdateError::InvalidTags);
+            }
+        }
+        Ok(())
+    }
+
     fn apply_to(self, video: &mut Video) {
-        let Self { name, public, game } = self;
+        let Self { name, public, game, description, tags } = self;
         if let Some(name) = name {
             video.name = Some(name.clone());
         }
@@ -600,6 +1115,13 @@ impl UpdateForm {
         if let Some(game) = game {
             video.game = game.clone();
         }
+        if let Some(description) = description {
+            let description = description.trim();
+            video.description = (!description.is_empty()).then(|| description.to_string());
+        }
+        if let Some(tags) = tags {
+            video.tags = normalize_tags(tags);
+        }
     }
 }
 
@@ -614,6 +1136,8 @@ impl ApiResponse for UpdateResponse {}
 #[serde(untagged)]
 pub(crate) enum UpdateError {
     VideoNotFound,
+    InvalidDescription,
+    InvalidTags,
     // UpdateError,
 }
 
@@ -621,6 +1145,8 @@ impl ApiErrorType for UpdateError {
     fn ty(&self) -> &'static str {
         match self {
             Self::VideoNotFound => "video_not_found",
+            Self::InvalidDescription => "invalid_description",
+            Self::InvalidTags => "invalid_tags",
             // Self::UpdateError => "update_error",
         }
     }
@@ -628,6 +1154,8 @@ impl ApiErrorType for UpdateError {
     fn status(&self) -> rocket::http::Status {
         match self {
             Self::VideoNotFound => rocket::http::Status::NotFound,
+            Self::InvalidDescription => rocket::http::Status::BadRequest,
+            Self::InvalidTags => rocket::http::Status::BadRequest,
             // Self::UpdateError => rocket::http::Status::InternalServerError,
         }
     }
@@ -635,6 +1163,8 @@ impl ApiErrorType for UpdateError {
     fn message(&self) -> String {
         match self {
             Self::VideoNotFound => "Video not found".to_string(),
+            Self::InvalidDescription => format!("Description can't be longer than {} characters", MAX_DESCRIPTION_LEN),
+            Self::InvalidTags => format!("At most {} tags of {} characters are allowed", MAX_TAGS, MAX_TAG_LEN),
             // Self::UpdateError => "Error while updating video".to_string(),
         }
     }
@@ -647,6 +1177,9 @@ pub(crate) async fn update(video: &str, form: Json<UpdateForm>, user: Result<Use
         Some(v) => v,
         None => return ApiResponder::Err(UpdateError::VideoNotFound.into()),
     };
+    if let Err(e) = form.validate() {
+        return ApiResponder::Err(e.into());
+    }
     let user_games = db.get_user_games_ids(&user).await?;
     if let Some(ref game) = form.game {
         // check if user is part of target game
@@ -657,7 +1190,7 @@ pub(crate) async fn update(video: &str, form: Json<UpdateForm>, user: Result<Use
     }
     // check if user owns the video or has permission to modify others' videos.
     //   if the user can modify others' videos, it will also need to be in the source game.
-    if video.owner == user.username || (user.allowed(Permissions::MODIFY_VIDEO_OTHERS) && user_games.contains(&video.game)){
+    if video.user_can_modify(&user, &user_games) {
         form.into_inner().apply_to(&mut video);
         db.update_video(&video).await?;
         UpdateResponse { inner: video }.into()
@@ -665,3 +1198,24 @@ pub(crate) async fn update(video: &str, form: Json<UpdateForm>, user: Result<Use
         AuthenticationError::InsufficientPermissions(Permissions::MODIFY_VIDEO_OTHERS).into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the same bytes uploaded to a second game reference the stored file by id, unresolved,
+    //   like the video stored with it
+    #[test]
+    fn duplicate_upload_shares_unresolved_file() {
+        let first = Video::uploaded("first".to_string(), Either::Left("file".to_string()), "a", "game_a", None, false);
+        let found = mongodb::bson::from_document::<Video>(mongodb::bson::to_document(&first).unwrap()).unwrap();
+        assert!(matches!(found.file, Either::Left(ref id) if id == "file"));
+
+        let second = Video::uploaded("second".to_string(), found.file, "b", "game_b", Some("copy".to_string()), true);
+        assert!(matches!(second.file, Either::Left(ref id) if id == "file"));
+        assert_eq!(second.game, "game_b");
+        assert_eq!(second.owner, "b");
+        let stored = mongodb::bson::to_document(&second).unwrap();
+        assert_eq!(stored.get_str("file").unwrap(), "file");
+    }
+}
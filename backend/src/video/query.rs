@@ -0,0 +1,202 @@
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use rocket_db_pools::mongodb::bson::{doc, Bson, Document};
+use serde::Serialize;
+
+use crate::{db::DBWrapper, response::ApiErrorType};
+
+#[derive(FromFormField, Debug, Clone, Copy, Default)]
+pub(crate) enum VideoSort {
+    #[default]
+    #[field(value = "added")]
+    Added,
+    #[field(value = "name")]
+    Name,
+    #[field(value = "duration")]
+    Duration,
+    #[field(value = "likes")]
+    Likes,
+}
+
+#[derive(FromFormField, Debug, Clone, Copy)]
+pub(crate) enum SortOrder {
+    #[field(value = "asc")]
+    Asc,
+    #[field(value = "desc")]
+    Desc,
+}
+
+// filters and sorting accepted by the video listing
+#[derive(FromForm, Debug)]
+pub(crate) struct VideoQuery {
+    game: Option<String>,
+    owner: Option<String>,
+    public: Option<bool>,
+    // rfc 3339 timestamps or plain dates
+    after: Option<String>,
+    before: Option<String>,
+    // seconds
+    min_duration: Option<f64>,
+    max_duration: Option<f64>,
+    // matches either the audio or the video codec
+    codec: Option<String>,
+    sort: Option<VideoSort>,
+    // defaults to descending for dates, durations and likes, ascending for names
+    order: Option<SortOrder>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum QueryError {
+    InvalidDate(String),
+}
+
+impl ApiErrorType for QueryError {
+    fn ty(&self) -> &'static str {
+        match self {
+            Self::InvalidDate(_) => "invalid_date",
+        }
+    }
+
+    fn status(&self) -> rocket::http::Status {
+        match self {
+            Self::InvalidDate(_) => rocket::http::Status::BadRequest,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::InvalidDate(d) => format!("Invalid date: {}", d),
+        }
+    }
+}
+
+fn parse_date(d: &str) -> Result<DateTime<Utc>, QueryError> {
+    DateTime::parse_from_rfc3339(d)
+        .map(|d| d.with_timezone(&Utc))
+        .or_else(|_| NaiveDate::parse_from_str(d, "%Y-%m-%d").map(|d| d.and_hms_opt(0, 0, 0).unwrap().and_utc()))
+        .map_err(|_| QueryError::InvalidDate(d.to_string()))
+}
+
+// `added` is stored as an rfc 3339 string, which sorts and compares like the dates it represents
+fn date_bound(d: &str) -> Result<String, QueryError> {
+    Ok(parse_date(d)?.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+}
+
+pub(crate) const KEY: &str = "_key";
+
+// the pipeline built from a query, the sort stages are kept apart so that the same filters can be counted
+pub(crate) struct QueryStages {
+    pub(crate) filter: Vec<Document>,
+    // stages computing the `_key` field results are sorted on
+    pub(crate) key: Vec<Document>,
+    pub(crate) order: i32,
+    // identifies the ordering in cursors
+    pub(crate) name: String,
+}
+
+impl VideoQuery {
+    fn needs_file(&self) -> bool {
+        self.min_duration.is_some()
+            || self.max_duration.is_some()
+            || self.codec.is_some()
+            || matches!(self.sort, Some(VideoSort::Duration))
+    }
+
+    pub(crate) fn stages(&self) -> Result<QueryStages, QueryError> {
+        let mut m = doc! {};
+        if let Some(ref game) = self.game {
+            m.insert("game", game);
+        }
+        if let Some(ref owner) = self.owner {
+            m.insert("owner", owner);
+        }
+        if let Some(public) = self.public {
+            m.insert("public", public);
+        }
+        let mut added = doc! {};
+        if let Some(ref after) = self.after {
+            added.insert("$gte", date_bound(after)?);
+        }
+        if let Some(ref before) = self.before {
+            added.insert("$lt", date_bound(before)?);
+        }
+        if !added.is_empty() {
+            m.insert("added", added);
+        }
+
+        let mut filter = vec![];
+        if !m.is_empty() {
+            filter.push(doc! {"$match": m});
+        }
+        if self.needs_file() {
+            filter.push(doc! {"$lookup": {
+                "from": DBWrapper::VIDEO_FILES,
+                "localField": "file",
+                "foreignField": "_id",
+                "as": "_file",
+            }});
+            filter.push(doc! {"$unwind": "$_file"});
+            let mut m = doc! {};
+            let mut duration = doc! {};
+            if let Some(min) = self.min_duration {
+                duration.insert("$gte", min);
+            }
+            if let Some(max) = self.max_duration {
+                duration.insert("$lte", max);
+            }
+            if !duration.is_empty() {
+                m.insert("_file.duration", duration);
+            }
+            if let Some(ref codec) = self.codec {
+                m.insert("$or", vec![doc! {"_file.video_codec": codec}, doc! {"_file.audio_codec": codec}]);
+            }
+            if !m.is_empty() {
+                filter.push(doc! {"$match": m});
+            }
+        }
+
+        let sort = self.sort.unwrap_or_default();
+        let order = match self.order {
+            Some(SortOrder::Asc) => 1,
+            Some(SortOrder::Desc) => -1,
+            None => if matches!(sort, VideoSort::Name) { 1 } else { -1 },
+        };
+        // the sort key is copied into `_key`, missing values are replaced so that cursors can compare them
+        let mut key = vec![];
+        let value = match sort {
+            VideoSort::Added => Bson::from("$added"),
+            VideoSort::Name => doc! {"$ifNull": ["$name", ""]}.into(),
+            VideoSort::Duration => doc! {"$ifNull": ["$_file.duration", -1.]}.into(),
+            VideoSort::Likes => {
+                key.push(doc! {"$lookup": {
+                    "from": DBWrapper::LIKES,
+                    "let": {"video": "$_id"},
+                    "pipeline": [
+                        {"$match": {"$expr": {"$eq": ["$video", "$$video"]}}},
+                        {"$count": "n"},
+                    ],
+                    "as": "_likes",
+                }});
+                doc! {"$ifNull": [{"$arrayElemAt": ["$_likes.n", 0]}, 0]}.into()
+            }
+        };
+        key.push(doc! {"$addFields": {KEY: value}});
+        Ok(QueryStages {
+            filter,
+            key,
+            order,
+            name: format!("{:?}:{}", sort, order),
+        })
+    }
+}
+
+impl QueryStages {
+    pub(crate) fn sort(&self) -> Document {
+        doc! {"$sort": {KEY: self.order, "_id": self.order}}
+    }
+
+    // fields added by the lookups are not part of `Video`, `_key` is kept to build the next cursor
+    pub(crate) fn cleanup() -> Document {
+        doc! {"$project": {"_file": 0, "_likes": 0}}
+    }
+}
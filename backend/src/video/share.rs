@@ -1,10 +1,42 @@
-use rocket::{http::Status, response::Responder};
+use std::{collections::HashMap, net::IpAddr, sync::Mutex};
 
-use crate::{db::DBWrapper, media::{MediaStream, Range, StreamError}};
+use chrono::{DateTime, TimeDelta, Utc};
+use lazy_static::lazy_static;
+use rocket::{form::Form, futures::TryStreamExt, http::{Cookie, CookieJar, SameSite, Status}, response::{content::RawHtml, Redirect, Responder}, serde::json::Json};
+use rocket_db_pools::mongodb::{self, bson::doc};
+use serde::{Deserialize, Serialize};
+
+use crate::{authentication::{AuthenticationError, UserGuard}, db::DBWrapper, media::{CachePolicy, MediaStream, Range, StreamError}, response::{ApiError, ApiErrorType, ApiResponder, ApiResponse}, user::{secure_rnd_string, Permissions, User}, CONFIG};
+
+use super::{analytics::{ViewSource, Viewer}, embed::{self, BaseUrl, WantsPage}, Video};
+
+// failed password attempts allowed per link and address within the window
+const MAX_FAILURES: u32 = 5;
+const FAILURE_WINDOW: TimeDelta = TimeDelta::minutes(15);
+
+// failed password attempts of an address for a link
+struct Failures {
+    count: u32,
+    first: DateTime<Utc>,
+}
+
+lazy_static! {
+    static ref FAILURES: Mutex<HashMap<(String, Option<IpAddr>), Failures>> = Mutex::new(HashMap::new());
+}
 
 pub(crate) enum ShareResponder {
     InternalError,
     NotFound,
+    // the link needs a password, or the given one is wrong
+    Unauthorized,
+    // the link expired, was revoked or used up its views
+    Gone,
+    // too many wrong passwords were tried
+    TooManyRequests,
+    Page(RawHtml<String>),
+    // asks for the password of a link
+    PasswordPage(RawHtml<String>),
+    Redirect(Redirect),
     Ok(Result<MediaStream, StreamError>),
 }
 
@@ -21,6 +53,28 @@ impl<'r> Responder<'r, 'r> for ShareResponder {
                     .status(Status::NotFound)
                     .ok()
             }
+            ShareResponder::Unauthorized => {
+                rocket::response::Response::build()
+                    .status(Status::Unauthorized)
+                    .ok()
+            }
+            ShareResponder::Gone => {
+                rocket::response::Response::build()
+                    .status(Status::Gone)
+                    .ok()
+            }
+            ShareResponder::TooManyRequests => {
+                rocket::response::Response::build()
+                    .status(Status::TooManyRequests)
+                    .ok()
+            }
+            ShareResponder::Page(page) => page.respond_to(request),
+            ShareResponder::PasswordPage(page) => {
+                rocket::response::Response::build_from(page.respond_to(request)?)
+                    .status(Status::Unauthorized)
+                    .ok()
+            }
+            ShareResponder::Redirect(r) => r.respond_to(request),
             ShareResponder::Ok(stream) => {
                 match stream {
                     Ok(s) => s.respond_to(request),
@@ -31,16 +85,24 @@ impl<'r> Responder<'r, 'r> for ShareResponder {
     }
 }
 
-#[get("/<video>")]
-pub(crate) async fn get(video: &str, db: DBWrapper, range: Option<Range>) -> ShareResponder {
+// browsers and link previews get a landing page embedding the video, players and `?raw=true` get the stream
+#[get("/<video>?<raw>")]
+pub(crate) async fn get(video: &str, raw: Option<bool>, wants_page: WantsPage, base: BaseUrl, db: DBWrapper, range: Option<Range>, ip: Option<IpAddr>) -> ShareResponder {
     match db.get_video_resolved(video).await {
         Ok(Some(mut v)) => {
             if !v.public {
                 return ShareResponder::NotFound;
             }
+            if wants_page.0 && !raw.unwrap_or(false) {
+                return match embed::page(&v, &base, &db).await {
+                    Ok(page) => ShareResponder::Page(page),
+                    Err(_) => ShareResponder::InternalError,
+                };
+            }
+            db.record_view(&v, Viewer::Address(ip), ViewSource::Share).await;
             // resolve conversion
             match v.resolve_converted(&db).await {
-                Ok(()) => ShareResponder::Ok(MediaStream::from_video(range, v).await),
+                Ok(()) => ShareResponder::Ok(MediaStream::from_video(range, v, CachePolicy::Public).await),
                 Err(_) => ShareResponder::InternalError,
             }
         }
@@ -48,3 +110,380 @@ pub(crate) async fn get(video: &str, db: DBWrapper, range: Option<Range>) -> Sha
         Err(_) => ShareResponder::InternalError,
     }
 }
+
+// a link giving access to a single video, public or not, under /share/link/<id>
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct ShareLink {
+    #[serde(rename = "_id")]
+    id: String,
+    video: String,
+    creator: String,
+    created: DateTime<Utc>,
+    expires: Option<DateTime<Utc>>,
+    password_hash: Option<String>,
+    max_views: Option<u32>,
+    #[serde(default)]
+    views: u32,
+    #[serde(default)]
+    revoked: bool,
+}
+
+impl ShareLink {
+    fn usable(&self) -> bool {
+        !self.revoked && self.expires.is_none_or(|e| e > Utc::now())
+    }
+
+    // holds the time until which the password doesn't need to be given again
+    fn cookie_name(&self) -> String {
+        format!("share_{}", self.id)
+    }
+
+    fn unlocked(&self, cookies: &CookieJar<'_>) -> bool {
+        self.password_hash.is_none() || cookies
+            .get_private(&self.cookie_name())
+            .and_then(|c| c.value().parse::<i64>().ok())
+            .is_some_and(|until| until > Utc::now().timestamp())
+    }
+
+    fn unlock(&self, cookies: &CookieJar<'_>) {
+        let until = Utc::now() + CONFIG.media_token_duration;
+        cookies.add_private(
+            Cookie::build((self.cookie_name(), until.timestamp().to_string()))
+                .path(format!("/share/link/{}", self.id))
+                .max_age(rocket::time::Duration::seconds(CONFIG.media_token_duration.num_seconds()))
+                .http_only(true)
+                .same_site(SameSite::Lax)
+        );
+    }
+}
+
+// last counted view of a link per viewer, expired through a ttl index once outside the view window
+#[derive(Serialize, Deserialize, Debug)]
+struct LinkView {
+    link: String,
+    viewer: String,
+    #[serde(with = "mongodb::bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    at: DateTime<Utc>,
+}
+
+impl DBWrapper {
+    async fn add_share_link(&self, link: &ShareLink) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<ShareLink>(Self::SHARE_LINKS)
+            .insert_one(link, None)
+            .await
+            .map(|_| ())
+    }
+
+    async fn get_share_link(&self, id: &str) -> Result<Option<ShareLink>, mongodb::error::Error> {
+        self
+            .collection::<ShareLink>(Self::SHARE_LINKS)
+            .find_one(doc! {"_id": id}, None)
+            .await
+    }
+
+    async fn get_video_share_links(&self, video: &str) -> Result<Vec<ShareLink>, mongodb::error::Error> {
+        self
+            .collection::<ShareLink>(Self::SHARE_LINKS)
+            .find(doc! {"video": video}, mongodb::options::FindOptions::builder().sort(doc! {"created": -1}).build())
+            .await?
+            .try_collect()
+            .await
+    }
+
+    async fn revoke_share_link(&self, id: &str) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<ShareLink>(Self::SHARE_LINKS)
+            .update_one(doc! {"_id": id}, doc! {"$set": {"revoked": true}}, None)
+            .await
+            .map(|_| ())
+    }
+
+    // players send many requests per playback, those of a viewer inside the view window count once.
+    //   returns whether the viewer may watch, false once the link used up its views
+    async fn count_share_link_view(&self, link: &ShareLink, viewer: &Viewer<'_>) -> Result<bool, mongodb::error::Error> {
+        let view = match self.start_recent_view(Self::SHARE_LINK_VIEWS, doc! {"link": &link.id, "viewer": viewer.key()}).await? {
+            Some(id) => id,
+            None => return Ok(true),
+        };
+        // the limit is checked by the update itself, concurrent viewers can't go past it
+        let mut filter = doc! {"_id": &link.id, "revoked": false};
+        if let Some(max) = link.max_views {
+            filter.insert("views", doc! {"$lt": max});
+        }
+        let counted = self
+            .collection::<ShareLink>(Self::SHARE_LINKS)
+            .find_one_and_update(filter, doc! {"$inc": {"views": 1}}, None)
+            .await?
+            .is_some();
+        if !counted {
+            // the viewer was turned away, their next requests must not pass as part of this view
+            self.collection::<LinkView>(Self::SHARE_LINK_VIEWS).delete_one(doc! {"_id": view}, None).await?;
+        }
+        Ok(counted)
+    }
+
+    pub(crate) async fn delete_video_share_links(&self, video: &str) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<ShareLink>(Self::SHARE_LINKS)
+            .delete_many(doc! {"video": video}, None)
+            .await
+            .map(|_| ())
+    }
+}
+
+// a link as shown to the users managing it, without the password hash
+#[derive(Serialize)]
+pub(crate) struct ShareLinkResponse {
+    id: String,
+    video: String,
+    creator: String,
+    created: DateTime<Utc>,
+    expires: Option<DateTime<Utc>>,
+    protected: bool,
+    max_views: Option<u32>,
+    views: u32,
+    revoked: bool,
+}
+
+impl From<ShareLink> for ShareLinkResponse {
+    fn from(link: ShareLink) -> Self {
+        Self {
+            id: link.id,
+            video: link.video,
+            creator: link.creator,
+            created: link.created,
+            expires: link.expires,
+            protected: link.password_hash.is_some(),
+            max_views: link.max_views,
+            views: link.views,
+            revoked: link.revoked,
+        }
+    }
+}
+
+impl ApiResponse for ShareLinkResponse {}
+
+#[derive(Serialize)]
+#[serde(transparent)]
+pub(crate) struct ShareLinksResponse {
+    inner: Vec<ShareLinkResponse>,
+}
+
+impl ApiResponse for ShareLinksResponse {}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum ShareError {
+    VideoNotFound,
+    LinkNotFound,
+    InvalidExpiry,
+    InvalidPassword,
+    InvalidMaxViews,
+}
+
+impl ApiErrorType for ShareError {
+    fn ty(&self) -> &'static str {
+        match self {
+            Self::VideoNotFound => "video_not_found",
+            Self::LinkNotFound => "link_not_found",
+            Self::InvalidExpiry => "invalid_expiry",
+            Self::InvalidPassword => "invalid_password",
+            Self::InvalidMaxViews => "invalid_max_views",
+        }
+    }
+
+    fn status(&self) -> rocket::http::Status {
+        match self {
+            Self::VideoNotFound => rocket::http::Status::NotFound,
+            Self::LinkNotFound => rocket::http::Status::NotFound,
+            Self::InvalidExpiry => rocket::http::Status::BadRequest,
+            Self::InvalidPassword => rocket::http::Status::BadRequest,
+            Self::InvalidMaxViews => rocket::http::Status::BadRequest,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::VideoNotFound => "Video not found".to_string(),
+            Self::LinkNotFound => "Share link not found".to_string(),
+            Self::InvalidExpiry => "Expiry date must be in the future".to_string(),
+            Self::InvalidPassword => "Password must not be empty".to_string(),
+            Self::InvalidMaxViews => "Maximum view count must be at least 1".to_string(),
+        }
+    }
+}
+
+// links are managed by the users allowed to modify the video
+async fn modifiable_video(video: &str, user: &User, db: &DBWrapper) -> Result<Video, ApiError> {
+    let video = db.get_video(video).await?.ok_or(ShareError::VideoNotFound)?;
+    let user_games = db.get_user_games_ids(user).await?;
+    if video.user_can_modify(user, &user_games) {
+        Ok(video)
+    } else {
+        Err(AuthenticationError::InsufficientPermissions(Permissions::MODIFY_VIDEO_OTHERS).into())
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateForm {
+    expires: Option<DateTime<Utc>>,
+    password: Option<String>,
+    max_views: Option<u32>,
+}
+
+#[post("/<video>/shares", data = "<form>", format = "json")]
+pub(crate) async fn create(video: &str, form: Json<CreateForm>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<ShareLinkResponse> {
+    let user = user?.user;
+    let video = match modifiable_video(video, &user, &db).await {
+        Ok(v) => v,
+        Err(e) => return ApiResponder::Err(e),
+    };
+    let form = form.into_inner();
+    if form.expires.is_some_and(|e| e <= Utc::now()) {
+        return ApiResponder::Err(ShareError::InvalidExpiry.into());
+    }
+    if form.password.as_ref().is_some_and(|p| p.is_empty()) {
+        return ApiResponder::Err(ShareError::InvalidPassword.into());
+    }
+    if form.max_views == Some(0) {
+        return ApiResponder::Err(ShareError::InvalidMaxViews.into());
+    }
+    let link = ShareLink {
+        id: secure_rnd_string(),
+        video: video.id,
+        creator: user.username,
+        created: Utc::now(),
+        expires: form.expires,
+        password_hash: form.password.map(User::password_hash),
+        max_views: form.max_views,
+        views: 0,
+        revoked: false,
+    };
+    db.add_share_link(&link).await?;
+    ShareLinkResponse::from(link).into()
+}
+
+#[get("/<video>/shares")]
+pub(crate) async fn list(video: &str, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<ShareLinksResponse> {
+    let user = user?.user;
+    let video = match modifiable_video(video, &user, &db).await {
+        Ok(v) => v,
+        Err(e) => return ApiResponder::Err(e),
+    };
+    let inner = db
+        .get_video_share_links(&video.id)
+        .await?
+        .into_iter()
+        .map(ShareLinkResponse::from)
+        .collect();
+    ShareLinksResponse { inner }.into()
+}
+
+// revoked links are kept, with their view count
+#[delete("/<video>/shares/<id>")]
+pub(crate) async fn revoke(video: &str, id: &str, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<()> {
+    let user = user?.user;
+    let video = match modifiable_video(video, &user, &db).await {
+        Ok(v) => v,
+        Err(e) => return ApiResponder::Err(e),
+    };
+    match db.get_share_link(id).await? {
+        Some(l) if l.video == video.id => {
+            db.revoke_share_link(&l.id).await?;
+            ApiResponder::Ok(())
+        }
+        _ => ApiResponder::Err(ShareError::LinkNotFound.into()),
+    }
+}
+
+// whether the address tried too many wrong passwords for the link lately
+fn throttled(link: &str, ip: Option<IpAddr>) -> bool {
+    FAILURES
+        .lock()
+        .unwrap()
+        .get(&(link.to_string(), ip))
+        .is_some_and(|f| f.count >= MAX_FAILURES && f.first > Utc::now() - FAILURE_WINDOW)
+}
+
+fn record_failure(link: &str, ip: Option<IpAddr>) {
+    let now = Utc::now();
+    let mut failures = FAILURES.lock().unwrap();
+    // forget the attempts outside the window, the map would grow forever otherwise
+    failures.retain(|_, f| f.first > now - FAILURE_WINDOW);
+    failures.entry((link.to_string(), ip)).or_insert(Failures { count: 0, first: now }).count += 1;
+}
+
+#[derive(FromForm)]
+pub(crate) struct UnlockForm<'r> {
+    password: &'r str,
+}
+
+// checks the password of a link once, then lets the browser stream it through a private cookie
+#[post("/link/<id>", data = "<form>")]
+pub(crate) async fn unlock(id: &str, form: Form<UnlockForm<'_>>, cookies: &CookieJar<'_>, wants_page: WantsPage, db: DBWrapper, ip: Option<IpAddr>) -> ShareResponder {
+    let link = match db.get_share_link(id).await {
+        Ok(Some(l)) => l,
+        Ok(None) => return ShareResponder::NotFound,
+        Err(_) => return ShareResponder::InternalError,
+    };
+    if !link.usable() {
+        return ShareResponder::Gone;
+    }
+    if let Some(ref hash) = link.password_hash {
+        if throttled(&link.id, ip) {
+            return ShareResponder::TooManyRequests;
+        }
+        // hashing is slow on purpose, it mustn't hold up the other requests
+        let (hash, password) = (hash.clone(), form.password.to_string());
+        let valid = rocket::tokio::task::spawn_blocking(move || User::verify_password_hash(&hash, &password))
+            .await
+            .unwrap_or(false);
+        if !valid {
+            record_failure(&link.id, ip);
+            return match wants_page.0 {
+                true => ShareResponder::PasswordPage(embed::password_page(true)),
+                false => ShareResponder::Unauthorized,
+            };
+        }
+        link.unlock(cookies);
+    }
+    ShareResponder::Redirect(Redirect::to(format!("/share/link/{}", link.id)))
+}
+
+// streams the video of a share link. protected links must be unlocked first,
+//   browsers are shown a form for the password
+#[get("/link/<id>")]
+pub(crate) async fn link(id: &str, cookies: &CookieJar<'_>, wants_page: WantsPage, db: DBWrapper, range: Option<Range>, ip: Option<IpAddr>) -> ShareResponder {
+    let link = match db.get_share_link(id).await {
+        Ok(Some(l)) => l,
+        Ok(None) => return ShareResponder::NotFound,
+        Err(_) => return ShareResponder::InternalError,
+    };
+    if !link.usable() {
+        return ShareResponder::Gone;
+    }
+    if !link.unlocked(cookies) {
+        return match wants_page.0 {
+            true => ShareResponder::PasswordPage(embed::password_page(false)),
+            false => ShareResponder::Unauthorized,
+        };
+    }
+    let mut video = match db.get_video_resolved(&link.video).await {
+        Ok(Some(v)) => v,
+        Ok(None) => return ShareResponder::NotFound,
+        Err(_) => return ShareResponder::InternalError,
+    };
+    let viewer = Viewer::Address(ip);
+    match db.count_share_link_view(&link, &viewer).await {
+        Ok(true) => {}
+        Ok(false) => return ShareResponder::Gone,
+        Err(_) => return ShareResponder::InternalError,
+    }
+    // analytics deduplicate views on their own, regardless of the link
+    db.record_view(&video, viewer, ViewSource::Share).await;
+    match video.resolve_converted(&db).await {
+        Ok(()) => ShareResponder::Ok(MediaStream::from_video(range, video, CachePolicy::Restricted).await),
+        Err(_) => ShareResponder::InternalError,
+    }
+}
@@ -0,0 +1,86 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::CONFIG;
+use crate::tools::Tool;
+
+use super::file::{probe_resolution, VideoFile};
+
+// layout of a tiled sprite sheet, enough to generate the webvtt track on request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct SpriteSheet {
+    // seconds between two frames
+    pub(crate) interval: f64,
+    pub(crate) count: u32,
+    pub(crate) columns: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+pub(crate) fn path(file: &str) -> PathBuf {
+    Path::new(&CONFIG.video_storage).join("sprites").join(format!("{}.jpg", file))
+}
+
+fn timestamp(t: f64) -> String {
+    let ms = (t * 1000.).round() as u64;
+    format!("{:02}:{:02}:{:02}.{:03}", ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000)
+}
+
+impl SpriteSheet {
+    // cues point to `sprite.jpg`, relative to the url the track is served from
+    pub(crate) fn webvtt(&self, duration: f64) -> String {
+        let mut out = String::from("WEBVTT\n\n");
+        for i in 0..self.count {
+            let start = i as f64 * self.interval;
+            let end = (start + self.interval).min(duration.max(start));
+            let x = i % self.columns * self.width;
+            let y = i / self.columns * self.height;
+            out.push_str(&format!("{} --> {}\nsprite.jpg#xywh={},{},{},{}\n\n", timestamp(start), timestamp(end), x, y, self.width, self.height));
+        }
+        out
+    }
+}
+
+pub(super) async fn generate(file: &VideoFile) -> Result<SpriteSheet, String> {
+    let duration = file.duration.ok_or("file has unknown duration")?;
+    let source = file.local_file().await.map_err(|e| e.to_string())?;
+    let (width, height) = probe_resolution(source.path()).await?;
+    // long videos get sparser frames instead of a huge sheet
+    let interval = CONFIG.sprites.interval.max(duration / CONFIG.sprites.max_frames as f64);
+    let count = ((duration / interval).ceil() as u32).max(1);
+    let columns = CONFIG.sprites.columns.min(count);
+    let rows = count.div_ceil(columns);
+    let w = CONFIG.sprites.width;
+    // keep aspect ratio, rounded to an even height like the scale filter does
+    let h = ((height as u64 * w as u64 / width as u64) as u32 + 1) & !1;
+
+    Tool::Ffmpeg.command()
+        .args(["-y", "-v", "error", "-i"])
+        .arg(source.path())
+        .args(["-map", "0:v:0", "-an", "-sn"])
+        .arg("-vf")
+        .arg(format!("fps=1/{},scale={}:{},tile={}x{}", interval, w, h, columns, rows))
+        .args(["-frames:v", "1", "-q:v", "5"])
+        .arg(path(&file.id))
+        .run()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(SpriteSheet {
+        interval,
+        count,
+        columns,
+        width: w,
+        height: h,
+    })
+}
+
+pub(super) fn delete(file: &str) -> Result<(), std::io::Error> {
+    let p = path(file);
+    if p.exists() {
+        std::fs::remove_file(p)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,311 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use rocket::futures::TryStreamExt;
+use rocket::{form::Form, fs::TempFile};
+use rocket_db_pools::mongodb::{self, bson::{doc, oid::ObjectId, serde_helpers::chrono_datetime_as_bson_datetime}};
+use serde::{Deserialize, Serialize};
+
+use crate::{authentication::{AuthenticationError, UserGuard}, config::CONFIG, db::DBWrapper, response::{ApiError, ApiErrorType, ApiResponder, ApiResponse}, tools::Tool, user::Permissions};
+
+use super::file::{CodecType, StreamInfo, VideoFile};
+
+// codecs ffmpeg can turn into webvtt, bitmap subtitles (pgs, dvd) would need ocr
+const TEXT_CODECS: &[&str] = &["subrip", "srt", "ass", "ssa", "webvtt", "mov_text", "text"];
+const MAX_UPLOAD_SIZE: u64 = 2 * 1024 * 1024;
+// demuxers allowed to read uploaded subtitles
+const UPLOAD_FORMATS: &str = "srt,webvtt,ass";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub(crate) enum SubtitleSource {
+    // extracted from the stream with the given index, shared by every video using the file
+    Embedded { file: String, index: u32 },
+    // uploaded by a user for a single video
+    External { video: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Subtitle {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub source: SubtitleSource,
+    pub language: Option<String>,
+    pub label: Option<String>,
+    pub default: bool,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub added: DateTime<Utc>,
+}
+
+impl ApiResponse for Subtitle {}
+
+pub(crate) fn path(id: &str) -> PathBuf {
+    Path::new(&CONFIG.video_storage).join("subtitles").join(format!("{}.vtt", id))
+}
+
+fn is_text(stream: &StreamInfo) -> bool {
+    matches!(stream.kind, CodecType::Subtitle) && TEXT_CODECS.contains(&stream.codec.as_str())
+}
+
+impl VideoFile {
+    pub(super) fn has_text_subtitles(&self) -> bool {
+        self.streams.iter().any(is_text)
+    }
+}
+
+// `format` is the demuxer of an uploaded file, which is only read with it
+async fn to_webvtt(source: &Path, map: Option<u32>, format: Option<&str>, target: &Path) -> Result<(), String> {
+    let mut cmd = Tool::Ffmpeg.command();
+    cmd.args(["-y", "-v", "error"]);
+    if let Some(format) = format {
+        cmd.untrusted_input(format).args(["-f", format]);
+    }
+    cmd
+        .arg("-i")
+        .arg(source);
+    if let Some(index) = map {
+        cmd.args(["-map", &format!("0:{}", index)]);
+    }
+    cmd
+        .args(["-f", "webvtt"])
+        .arg(target)
+        .timeout(CONFIG.tools.probe_timeout)
+        .run()
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+// demuxer of an uploaded file, which must be one of the subtitle formats
+async fn probe_upload(source: &Path) -> Result<String, String> {
+    let out = Tool::Ffprobe.command()
+        .args(["-v", "error", "-show_entries", "format=format_name", "-of", "csv=p=0"])
+        .untrusted_input(UPLOAD_FORMATS)
+        .arg(source)
+        .run()
+        .await
+        .map_err(|e| e.to_string())?;
+    let format = String::from_utf8_lossy(&out).trim().to_string();
+    match UPLOAD_FORMATS.split(',').any(|f| f == format) {
+        true => Ok(format),
+        false => Err(format!("unexpected format {}", format)),
+    }
+}
+
+// extracts every text subtitle track of the file, replacing the ones extracted before
+pub(super) async fn extract(file: &VideoFile, db: &DBWrapper) -> Result<usize, String> {
+    db.delete_embedded_subtitles(&file.id).await.map_err(|e| e.to_string())?;
+    let source = file.local_file().await.map_err(|e| e.to_string())?;
+    let mut count = 0;
+    for stream in file.streams.iter().filter(|s| is_text(s)) {
+        let id = ObjectId::new().to_hex();
+        let target = path(&id);
+        if let Err(e) = to_webvtt(source.path(), Some(stream.index), None, &target).await {
+            let _ = std::fs::remove_file(&target);
+            return Err(format!("stream {}: {}", stream.index, e));
+        }
+        let sub = Subtitle {
+            id,
+            source: SubtitleSource::Embedded { file: file.id.clone(), index: stream.index },
+            language: stream.language.clone(),
+            label: stream.title.clone(),
+            default: stream.default,
+            added: Utc::now(),
+        };
+        if let Err(e) = db.insert_subtitle(&sub).await {
+            let _ = std::fs::remove_file(&target);
+            return Err(e.to_string());
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn remove_files(subs: &[Subtitle]) {
+    for sub in subs {
+        if let Err(e) = std::fs::remove_file(path(&sub.id)) {
+            log::error!("error while deleting subtitle {}: {}", sub.id, e);
+        }
+    }
+}
+
+impl DBWrapper {
+    async fn insert_subtitle(&self, sub: &Subtitle) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<Subtitle>(Self::SUBTITLES)
+            .insert_one(sub, None)
+            .await
+            .map(|_| ())
+    }
+
+    // tracks available for a video, its own uploads first
+    pub(crate) async fn get_subtitles(&self, video: &str, file: &str) -> Result<Vec<Subtitle>, mongodb::error::Error> {
+        let mut subs: Vec<Subtitle> = self
+            .collection::<Subtitle>(Self::SUBTITLES)
+            .find(doc! { "$or": [
+                { "source.type": "external", "source.video": video },
+                { "source.type": "embedded", "source.file": file },
+            ] }, None)
+            .await?
+            .try_collect()
+            .await?;
+        subs.sort_by_key(|s| (matches!(s.source, SubtitleSource::Embedded { .. }), s.added));
+        Ok(subs)
+    }
+
+    async fn delete_subtitles(&self, filter: mongodb::bson::Document) -> Result<(), mongodb::error::Error> {
+        let subs: Vec<Subtitle> = self
+            .collection::<Subtitle>(Self::SUBTITLES)
+            .find(filter.clone(), None)
+            .await?
+            .try_collect()
+            .await?;
+        self
+            .collection::<Subtitle>(Self::SUBTITLES)
+            .delete_many(filter, None)
+            .await?;
+        remove_files(&subs);
+        Ok(())
+    }
+
+    pub(super) async fn delete_embedded_subtitles(&self, file: &str) -> Result<(), mongodb::error::Error> {
+        self.delete_subtitles(doc! { "source.type": "embedded", "source.file": file }).await
+    }
+
+    pub(super) async fn delete_video_subtitles(&self, video: &str) -> Result<(), mongodb::error::Error> {
+        self.delete_subtitles(doc! { "source.type": "external", "source.video": video }).await
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum SubtitleError {
+    VideoNotFound,
+    SubtitleNotFound,
+    InvalidFile(String),
+}
+
+impl ApiErrorType for SubtitleError {
+    fn ty(&self) -> &'static str {
+        match self {
+            Self::VideoNotFound => "video_not_found",
+            Self::SubtitleNotFound => "subtitle_not_found",
+            Self::InvalidFile(_) => "invalid_file",
+        }
+    }
+
+    fn status(&self) -> rocket::http::Status {
+        match self {
+            Self::VideoNotFound => rocket::http::Status::NotFound,
+            Self::SubtitleNotFound => rocket::http::Status::NotFound,
+            Self::InvalidFile(_) => rocket::http::Status::BadRequest,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::VideoNotFound => "Video not found".to_string(),
+            Self::SubtitleNotFound => "Subtitle not found".to_string(),
+            Self::InvalidFile(s) => format!("Invalid subtitle file: {}", s),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(transparent)]
+pub(crate) struct ListResponse {
+    inner: Vec<Subtitle>,
+}
+
+impl ApiResponse for ListResponse {}
+
+#[get("/<video>/subtitles")]
+pub(crate) async fn list(video: &str, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<ListResponse> {
+    let user = user?.user;
+    let video = match db.get_video_resolved(video).await? {
+        Some(v) => v,
+        None => return ApiResponder::Err(SubtitleError::VideoNotFound.into()),
+    };
+    if !video.user_authorized(Some(&user), &db).await? {
+        return AuthenticationError::InsufficientPermissions(Permissions::WATCH_VIDEO).into();
+    }
+    let file = video.file.unwrap_right();
+    ListResponse { inner: db.get_subtitles(&video.id, &file.id).await? }.into()
+}
+
+#[derive(FromForm)]
+pub(crate) struct UploadForm<'r> {
+    file: TempFile<'r>,
+    language: Option<String>,
+    label: Option<String>,
+}
+
+#[post("/<video>/subtitles", data = "<form>")]
+pub(crate) async fn upload(video: &str, form: Form<UploadForm<'_>>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<Subtitle> {
+    let user = user?.user;
+    let video = match db.get_video(video).await? {
+        Some(v) => v,
+        None => return ApiResponder::Err(SubtitleError::VideoNotFound.into()),
+    };
+    if !video.user_can_modify(&user, &db.get_user_games_ids(&user).await?) {
+        return AuthenticationError::InsufficientPermissions(Permissions::MODIFY_VIDEO_OTHERS).into();
+    }
+    let form = form.into_inner();
+    if form.file.len() > MAX_UPLOAD_SIZE {
+        return ApiResponder::Err(SubtitleError::InvalidFile("file too large".to_string()).into());
+    }
+    let source = match form.file.path() {
+        Some(p) => p,
+        None => return ApiResponder::Err(SubtitleError::InvalidFile("empty file".to_string()).into()),
+    };
+    // the format is detected among the subtitle ones only, converting also validates the content
+    let id = ObjectId::new().to_hex();
+    let target = path(&id);
+    let converted = match probe_upload(source).await {
+        Ok(format) => to_webvtt(source, None, Some(&format), &target).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = converted {
+        let _ = std::fs::remove_file(&target);
+        // ffmpeg's output can contain server paths, only the log gets it
+        log::warn!("uploaded subtitles for video {} can't be converted: {}", video.id, e);
+        return ApiResponder::Err(SubtitleError::InvalidFile("unsupported or malformed subtitles".to_string()).into());
+    }
+    let sub = Subtitle {
+        id,
+        source: SubtitleSource::External { video: video.id },
+        language: form.language.filter(|l| !l.trim().is_empty()),
+        label: form.label.filter(|l| !l.trim().is_empty()),
+        default: false,
+        added: Utc::now(),
+    };
+    if let Err(e) = db.insert_subtitle(&sub).await {
+        let _ = std::fs::remove_file(&target);
+        return ApiResponder::Err(ApiError::from(e));
+    }
+    sub.into()
+}
+
+#[delete("/<video>/subtitles/<id>")]
+pub(crate) async fn delete(video: &str, id: &str, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> ApiResponder<Subtitle> {
+    let user = user?.user;
+    let video = match db.get_video(video).await? {
+        Some(v) => v,
+        None => return ApiResponder::Err(SubtitleError::VideoNotFound.into()),
+    };
+    if !video.user_can_modify(&user, &db.get_user_games_ids(&user).await?) {
+        return AuthenticationError::InsufficientPermissions(Permissions::MODIFY_VIDEO_OTHERS).into();
+    }
+    // embedded tracks belong to the file and can't be removed
+    let sub = db
+        .collection::<Subtitle>(DBWrapper::SUBTITLES)
+        .find_one_and_delete(doc! { "_id": id, "source.type": "external", "source.video": &video.id }, None)
+        .await?;
+    match sub {
+        Some(sub) => {
+            remove_files(std::slice::from_ref(&sub));
+            sub.into()
+        }
+        None => ApiResponder::Err(SubtitleError::SubtitleNotFound.into()),
+    }
+}
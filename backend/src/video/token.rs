@@ -1,19 +1,27 @@
-use rocket_db_pools::mongodb::{self, bson::doc};
+use rocket_db_pools::mongodb::{self, bson::doc, options::{FindOneAndUpdateOptions, ReturnDocument}};
 use serde::{Deserialize, Serialize};
 
-use crate::{db::DBWrapper, user::ExpiringToken, CONFIG};
+use crate::{db::DBWrapper, user::{ExpiringToken, User}, CONFIG};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct VideoToken {
     pub(crate) token: ExpiringToken,
     pub(crate) video: String,
+    // user the token was issued to, if logged in
+    #[serde(default)]
+    pub(crate) user: Option<String>,
+    // set on the first request serving the video, which is counted as a view
+    #[serde(default)]
+    pub(crate) used: bool,
 }
 
 impl VideoToken {
-    pub(crate) fn new(video: &str) -> Self {
+    pub(crate) fn new(video: &str, user: Option<&User>) -> Self {
         Self {
             token: ExpiringToken::new(CONFIG.media_token_duration),
             video: video.to_string(),
+            user: user.map(|u| u.username.clone()),
+            used: false,
         }
     }
 }
@@ -33,4 +41,16 @@ impl DBWrapper {
             .find_one(doc! {"token.token": token}, None)
             .await
     }
+
+    // marks the token as used, returns it only the first time
+    pub(crate) async fn use_video_token(&self, token: &str) -> Result<Option<VideoToken>, mongodb::error::Error> {
+        self
+            .collection(Self::VIDEO_TOKENS)
+            .find_one_and_update(
+                doc! {"token.token": token, "used": {"$ne": true}},
+                doc! {"$set": {"used": true}},
+                FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build(),
+            )
+            .await
+    }
 }
@@ -0,0 +1,494 @@
+// resumable uploads, following the tus 1.0 protocol (core, creation, expiration and termination extensions)
+//   https://tus.io/protocols/resumable-upload
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::ops::FromResidual;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::Responder;
+use rocket::tokio::fs::OpenOptions;
+use rocket::{Orbit, Rocket};
+use rocket_db_pools::mongodb::{self, bson::{doc, oid::ObjectId, serde_helpers::chrono_datetime_as_bson_datetime}};
+use serde::{Deserialize, Serialize};
+
+use crate::{authentication::{AuthenticationError, UserGuard}, config::CONFIG, db::DBWrapper, response::{ApiError, ApiErrorType, ApiResponder}, user::Permissions};
+
+use super::{ingest, IngestSource, UploadError};
+
+const VERSION: &str = "1.0.0";
+const EXTENSIONS: &str = "creation,expiration,termination";
+
+lazy_static! {
+    // sessions with a request writing to their file, see `SessionLock`
+    static ref LOCKED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+// held while a request checks the offset and appends to a session's file, or removes it.
+//   concurrent requests would interleave their writes and corrupt the file
+struct SessionLock(String);
+
+impl SessionLock {
+    // none if another request holds it
+    fn claim(id: &str) -> Option<Self> {
+        LOCKED.lock().unwrap().insert(id.to_string()).then(|| Self(id.to_string()))
+    }
+}
+
+impl Drop for SessionLock {
+    fn drop(&mut self) {
+        LOCKED.lock().unwrap().remove(&self.0);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct UploadSession {
+    #[serde(rename = "_id")]
+    id: String,
+    user: String,
+    game: String,
+    name: Option<String>,
+    public: bool,
+    length: u64,
+    offset: u64,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    created: DateTime<Utc>,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    expires: DateTime<Utc>,
+    // id of the created video, once the upload is complete
+    video: Option<String>,
+}
+
+impl UploadSession {
+    fn path(&self) -> PathBuf {
+        Path::new(&CONFIG.video_storage).join("uploads").join(&self.id)
+    }
+
+    // the partial file is the source of truth: an interrupted request may have written
+    //   more bytes than the ones recorded in the db.
+    async fn current_offset(&self) -> u64 {
+        match rocket::tokio::fs::metadata(self.path()).await {
+            Ok(m) => m.len(),
+            Err(_) => self.offset,
+        }
+    }
+
+    fn expires_header(&self) -> String {
+        self.expires.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+    }
+}
+
+impl DBWrapper {
+    async fn insert_upload_session(&self, session: &UploadSession) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<UploadSession>(Self::UPLOAD_SESSIONS)
+            .insert_one(session, None)
+            .await
+            .map(|_| ())
+    }
+
+    async fn get_upload_session(&self, id: &str, user: &str) -> Result<Option<UploadSession>, mongodb::error::Error> {
+        self
+            .collection::<UploadSession>(Self::UPLOAD_SESSIONS)
+            .find_one(doc! {"_id": id, "user": user}, None)
+            .await
+    }
+
+    async fn update_upload_session(&self, session: &UploadSession) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<UploadSession>(Self::UPLOAD_SESSIONS)
+            .replace_one(doc! {"_id": &session.id}, session, None)
+            .await
+            .map(|_| ())
+    }
+
+    async fn delete_upload_session(&self, session: &UploadSession) -> Result<(), mongodb::error::Error> {
+        self
+            .collection::<UploadSession>(Self::UPLOAD_SESSIONS)
+            .delete_one(doc! {"_id": &session.id}, None)
+            .await
+            .map(|_| ())
+    }
+
+    async fn take_expired_upload_sessions(&self) -> Result<Vec<UploadSession>, mongodb::error::Error> {
+        use rocket::futures::TryStreamExt;
+        let filter = doc! {"expires": {"$lt": mongodb::bson::DateTime::now()}};
+        let expired: Vec<UploadSession> = self
+            .collection::<UploadSession>(Self::UPLOAD_SESSIONS)
+            .find(filter, None)
+            .await?
+            .try_collect()
+            .await?;
+        self
+            .collection::<UploadSession>(Self::UPLOAD_SESSIONS)
+            .delete_many(doc! {"_id": {"$in": expired.iter().map(|s| s.id.as_str()).collect::<Vec<_>>()}}, None)
+            .await?;
+        Ok(expired)
+    }
+}
+
+async fn cleanup(db: DBWrapper) {
+    // no need to be precise here, abandoned uploads just take some disk space
+    let mut interval = rocket::tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        match db.take_expired_upload_sessions().await {
+            Ok(expired) => for s in expired {
+                if s.path().exists() {
+                    if let Err(e) = std::fs::remove_file(s.path()) {
+                        log::error!("failed to remove expired upload {}: {}", s.id, e);
+                    }
+                }
+                log::info!("removed expired upload session {}", s.id);
+            }
+            Err(e) => log::error!("failed to fetch expired upload sessions: {}", e),
+        }
+    }
+}
+
+pub(crate) fn spawn_cleanup(rocket: &Rocket<Orbit>) {
+    match DBWrapper::fetch(rocket) {
+        Some(db) => {
+            rocket::tokio::spawn(cleanup(db));
+        }
+        None => log::error!("Failed to fetch database connection for upload cleanup"),
+    }
+}
+
+pub(crate) struct TusHeaders<'r> {
+    resumable: Option<&'r str>,
+    length: Option<&'r str>,
+    offset: Option<&'r str>,
+    metadata: Option<&'r str>,
+    content_type: Option<&'r str>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TusHeaders<'r> {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        let h = request.headers();
+        Outcome::Success(Self {
+            resumable: h.get_one("Tus-Resumable"),
+            length: h.get_one("Upload-Length"),
+            offset: h.get_one("Upload-Offset"),
+            metadata: h.get_one("Upload-Metadata"),
+            content_type: h.get_one("Content-Type"),
+        })
+    }
+}
+
+impl TusHeaders<'_> {
+    fn check_version(&self) -> Result<(), TusError> {
+        match self.resumable {
+            Some(VERSION) => Ok(()),
+            _ => Err(TusError::UnsupportedVersion),
+        }
+    }
+
+    fn metadata(&self) -> Result<HashMap<String, String>, TusError> {
+        parse_metadata(self.metadata.unwrap_or_default())
+    }
+}
+
+// comma separated list of `key base64(value)`, value may be omitted
+fn parse_metadata(header: &str) -> Result<HashMap<String, String>, TusError> {
+    let mut out = HashMap::new();
+    for pair in header.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let mut parts = pair.split(' ');
+        let key = parts.next().unwrap_or_default();
+        let value = match (parts.next(), parts.next()) {
+            (Some(v), None) => {
+                let v = BASE64_STANDARD.decode(v).map_err(|_| TusError::InvalidMetadata(key.to_string()))?;
+                String::from_utf8(v).map_err(|_| TusError::InvalidMetadata(key.to_string()))?
+            }
+            (None, _) => String::new(),
+            // keys can't contain spaces, neither can values
+            (Some(_), Some(_)) => return Err(TusError::InvalidMetadata(key.to_string())),
+        };
+        out.insert(key.to_string(), value);
+    }
+    Ok(out)
+}
+
+// bytes a patch may still append, a file that somehow grew past the length takes none
+fn remaining(length: u64, offset: u64) -> u64 {
+    length.saturating_sub(offset)
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) enum TusError {
+    UnsupportedVersion,
+    MissingLength,
+    InvalidMetadata(String),
+    TooLarge,
+    UploadNotFound,
+    OffsetMismatch,
+    InvalidContentType,
+    Locked,
+}
+
+impl ApiErrorType for TusError {
+    fn ty(&self) -> &'static str {
+        match self {
+            Self::UnsupportedVersion => "unsupported_version",
+            Self::MissingLength => "missing_length",
+            Self::InvalidMetadata(_) => "invalid_metadata",
+            Self::TooLarge => "too_large",
+            Self::UploadNotFound => "upload_not_found",
+            Self::OffsetMismatch => "offset_mismatch",
+            Self::InvalidContentType => "invalid_content_type",
+            Self::Locked => "locked",
+        }
+    }
+
+    fn status(&self) -> Status {
+        match self {
+            Self::UnsupportedVersion => Status::PreconditionFailed,
+            Self::MissingLength => Status::BadRequest,
+            Self::InvalidMetadata(_) => Status::BadRequest,
+            Self::TooLarge => Status::PayloadTooLarge,
+            Self::UploadNotFound => Status::NotFound,
+            Self::OffsetMismatch => Status::Conflict,
+            Self::InvalidContentType => Status::UnsupportedMediaType,
+            Self::Locked => Status::Locked,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::UnsupportedVersion => format!("Only tus version {} is supported", VERSION),
+            Self::MissingLength => "Missing or invalid Upload-Length header".to_string(),
+            Self::InvalidMetadata(k) => format!("Invalid Upload-Metadata value for key: {}", k),
+            Self::TooLarge => "Upload exceeds the maximum allowed size".to_string(),
+            Self::UploadNotFound => "Upload not found or expired".to_string(),
+            Self::OffsetMismatch => "Upload-Offset doesn't match the current upload offset".to_string(),
+            Self::InvalidContentType => "Content-Type must be application/offset+octet-stream".to_string(),
+            Self::Locked => "Another request is writing to this upload".to_string(),
+        }
+    }
+}
+
+pub(crate) enum TusResponder {
+    Ok(Status, Vec<(&'static str, String)>),
+    Err(ApiError),
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for TusResponder {
+    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
+        let mut res = match self {
+            Self::Ok(status, headers) => {
+                let mut build = rocket::Response::build();
+                build.status(status);
+                for (key, value) in headers {
+                    build.header(Header::new(key, value));
+                }
+                build.finalize()
+            }
+            Self::Err(e) => ApiResponder::<()>::Err(e).respond_to(request)?,
+        };
+        res.set_header(Header::new("Tus-Resumable", VERSION));
+        Ok(res)
+    }
+}
+
+impl<E> FromResidual<Result<Infallible, E>> for TusResponder where E: ApiErrorType {
+    fn from_residual(residual: Result<Infallible, E>) -> Self {
+        Self::Err(residual.map_err(Into::into).unwrap_err())
+    }
+}
+
+impl FromResidual<Result<Infallible, ApiError>> for TusResponder {
+    fn from_residual(residual: Result<Infallible, ApiError>) -> Self {
+        Self::Err(residual.unwrap_err())
+    }
+}
+
+impl From<AuthenticationError> for TusResponder {
+    fn from(e: AuthenticationError) -> Self {
+        Self::Err(e.into())
+    }
+}
+
+#[options("/uploads")]
+pub(crate) async fn options() -> TusResponder {
+    let mut headers = vec![
+        ("Tus-Version", VERSION.to_string()),
+        ("Tus-Extension", EXTENSIONS.to_string()),
+    ];
+    if let Some(max) = CONFIG.uploads.max_size {
+        headers.push(("Tus-Max-Size", max.to_string()));
+    }
+    TusResponder::Ok(Status::NoContent, headers)
+}
+
+#[post("/uploads")]
+pub(crate) async fn create(headers: TusHeaders<'_>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> TusResponder {
+    let user = user?.user;
+    headers.check_version()?;
+    // check if user has permission to upload
+    if !user.allowed(Permissions::ADD_VIDEOS) {
+        return AuthenticationError::InsufficientPermissions(Permissions::ADD_VIDEOS).into();
+    }
+    let length = headers.length
+        .and_then(|l| l.parse::<u64>().ok())
+        .ok_or(TusError::MissingLength)?;
+    if CONFIG.uploads.max_size.is_some_and(|m| length > m) {
+        return TusResponder::Err(TusError::TooLarge.into());
+    }
+    let mut metadata = headers.metadata()?;
+    let game = metadata.remove("game").ok_or(TusError::InvalidMetadata("game".to_string()))?;
+    // check if game exists
+    //    this also checks if user is in the game group
+    if !db.get_user_games_ids(&user).await?.contains(&game) {
+        return TusResponder::Err(UploadError::GameNotFound.into());
+    }
+    // fail early, the quota is checked again once the upload is complete
+    if let Err(e) = db.check_quota(&user, &game, length).await {
+        return TusResponder::Err(e);
+    }
+    // tus clients usually send the original file name as `filename`
+    let name = metadata.remove("name").or(metadata.remove("filename"));
+    let public = matches!(metadata.get("public").map(String::as_str), Some("true") | Some("1"));
+
+    let now = Utc::now();
+    let session = UploadSession {
+        id: ObjectId::new().to_hex(),
+        user: user.username,
+        game,
+        name,
+        public,
+        length,
+        offset: 0,
+        created: now,
+        expires: now + CONFIG.uploads.expiration,
+        video: None,
+    };
+    rocket::tokio::fs::File::create(session.path()).await.map_err(ApiError::from)?;
+    db.insert_upload_session(&session).await?;
+    TusResponder::Ok(Status::Created, vec![
+        ("Location", uri!("/api/video", head(&session.id)).to_string()),
+        ("Upload-Expires", session.expires_header()),
+    ])
+}
+
+#[head("/uploads/<id>")]
+pub(crate) async fn head(id: &str, headers: TusHeaders<'_>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> TusResponder {
+    let user = user?.user;
+    headers.check_version()?;
+    let session = db.get_upload_session(id, &user.username).await?.ok_or(TusError::UploadNotFound)?;
+    let mut headers = vec![
+        ("Upload-Offset", session.current_offset().await.to_string()),
+        ("Upload-Length", session.length.to_string()),
+        ("Upload-Expires", session.expires_header()),
+        ("Cache-Control", "no-store".to_string()),
+    ];
+    if let Some(video) = session.video {
+        headers.push(("Upload-Video", video));
+    }
+    TusResponder::Ok(Status::Ok, headers)
+}
+
+#[patch("/uploads/<id>", data = "<data>")]
+pub(crate) async fn patch(id: &str, data: Data<'_>, headers: TusHeaders<'_>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> TusResponder {
+    let user = user?.user;
+    headers.check_version()?;
+    if headers.content_type != Some("application/offset+octet-stream") {
+        return TusResponder::Err(TusError::InvalidContentType.into());
+    }
+    // claimed before reading the session, and kept until the upload is ingested so that it's ingested once
+    let _lock = SessionLock::claim(id).ok_or(TusError::Locked)?;
+    let mut session = db.get_upload_session(id, &user.username).await?.ok_or(TusError::UploadNotFound)?;
+    let offset = session.current_offset().await;
+    if session.video.is_some() || headers.offset.and_then(|o| o.parse::<u64>().ok()) != Some(offset) {
+        return TusResponder::Err(TusError::OffsetMismatch.into());
+    }
+
+    let mut file = OpenOptions::new().append(true).open(session.path()).await.map_err(ApiError::from)?;
+    let written = data.open(remaining(session.length, offset).bytes()).stream_to(&mut file).await;
+    drop(file);
+    // even if the stream was interrupted, what reached the disk is kept for the next request
+    session.offset = session.current_offset().await;
+    session.expires = Utc::now() + CONFIG.uploads.expiration;
+    db.update_upload_session(&session).await?;
+    written.map_err(ApiError::from)?;
+
+    let mut headers = vec![
+        ("Upload-Offset", session.offset.to_string()),
+        ("Upload-Expires", session.expires_header()),
+    ];
+    if session.offset == session.length {
+        let path = session.path();
+        match ingest(&db, &user, &session.game, session.name.clone(), session.public, IngestSource::Path(&path)).await {
+            Ok(video) => {
+                session.video = Some(video.id.clone());
+                db.update_upload_session(&session).await?;
+                headers.push(("Upload-Video", video.id));
+            }
+            Err(e) => {
+                // the uploaded file is unusable, there is nothing to resume
+                db.delete_upload_session(&session).await?;
+                let _ = std::fs::remove_file(&path);
+                return TusResponder::Err(e);
+            }
+        }
+    }
+    TusResponder::Ok(Status::NoContent, headers)
+}
+
+// ranked to avoid clashing with `like::delete` on /<video>/like
+#[delete("/uploads/<id>", rank = 1)]
+pub(crate) async fn terminate(id: &str, headers: TusHeaders<'_>, user: Result<UserGuard<()>, AuthenticationError>, db: DBWrapper) -> TusResponder {
+    let user = user?.user;
+    headers.check_version()?;
+    let _lock = SessionLock::claim(id).ok_or(TusError::Locked)?;
+    let session = db.get_upload_session(id, &user.username).await?.ok_or(TusError::UploadNotFound)?;
+    db.delete_upload_session(&session).await?;
+    if session.path().exists() {
+        std::fs::remove_file(session.path()).map_err(ApiError::from)?;
+    }
+    TusResponder::Ok(Status::NoContent, vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_pairs() {
+        let m = parse_metadata("game Z2FtZQ==, name bXkgY2xpcA==,public").unwrap();
+        assert_eq!(m["game"], "game");
+        assert_eq!(m["name"], "my clip");
+        // keys without a value are kept, empty
+        assert_eq!(m["public"], "");
+        assert!(parse_metadata("").unwrap().is_empty());
+        assert!(parse_metadata(" , ,").unwrap().is_empty());
+    }
+
+    #[test]
+    fn metadata_malformed_pair() {
+        assert!(matches!(parse_metadata("game Z2FtZQ== extra"), Err(TusError::InvalidMetadata(k)) if k == "game"));
+    }
+
+    #[test]
+    fn metadata_invalid_base64() {
+        assert!(matches!(parse_metadata("game not*base64"), Err(TusError::InvalidMetadata(k)) if k == "game"));
+        // valid base64, but not utf-8
+        assert!(matches!(parse_metadata("name //8="), Err(TusError::InvalidMetadata(k)) if k == "name"));
+    }
+
+    #[test]
+    fn patch_stops_at_length() {
+        assert_eq!(remaining(100, 0), 100);
+        assert_eq!(remaining(100, 40), 60);
+        assert_eq!(remaining(100, 100), 0);
+        assert_eq!(remaining(100, 120), 0);
+    }
+}
@@ -264,7 +264,7 @@ class _VideoTileState extends State<VideoTile> {
       },
       child: ListTile(
         leading: CachedNetworkImage(
-          imageUrl: "${AuthService.baseUrl}/video/${widget.video['file']['_id']}/thumb",
+          imageUrl: "${AuthService.baseUrl}/video/${widget.video['_id']}/thumb",
           placeholder: (context, url) => CircularProgressIndicator(),
           errorWidget: (context, url, error) => Image.asset("assets/thumb.png"),
         ),